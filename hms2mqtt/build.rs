@@ -5,11 +5,29 @@ static MOD_RS: &[u8] = b"
 /// Generated from protobuf.
 pub mod RealData;
 /// Generated from protobuf.
+pub mod RealDataNew;
+/// Generated from protobuf.
 pub mod GetConfig;
+/// Generated from protobuf.
+pub mod APPInformationData;
+/// Generated from protobuf.
+pub mod CommandPB;
+/// Generated from protobuf.
+pub mod WarnData;
+/// Generated from protobuf.
+pub mod AppGetHistPower;
 ";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let proto_files = ["src/protos/RealData.proto"];
+    let proto_files = [
+        "src/protos/RealData.proto",
+        "src/protos/RealDataNew.proto",
+        "src/protos/GetConfig.proto",
+        "src/protos/APPInformationData.proto",
+        "src/protos/CommandPB.proto",
+        "src/protos/WarnData.proto",
+        "src/protos/AppGetHistPower.proto",
+    ];
 
     for path in &proto_files {
         println!("cargo:rerun-if-changed={path}");
@@ -0,0 +1,128 @@
+use crate::protos::hoymiles::WarnData::WInfoMO;
+use crate::reading::serial_to_string;
+use serde::Serialize;
+
+/// `Alarm` is an entry of the alarm log of a DTU, e.g. a grid over-voltage.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alarm {
+    pub inverter_serial: String,
+    pub code: i32,
+    /// Running number of the alarm, which tells apart repeated alarms with the same code
+    pub number: i32,
+    pub text: String,
+    /// Start and end time as reported by the DTU, the end is 0 while the alarm is active
+    pub start_time: i32,
+    pub end_time: i32,
+}
+
+// Texts of the alarm codes as documented by Hoymiles for its HM, HMS and HMT inverters.
+const ALARM_TEXTS: [(i32, &str); 72] = [
+    (1, "Inverter start"),
+    (2, "DTU command failed"),
+    (73, "Temperature above 80°C"),
+    (121, "Over temperature protection"),
+    (124, "Shut down by remote control"),
+    (125, "Grid configuration parameter error"),
+    (126, "Software error code 126"),
+    (127, "Firmware error"),
+    (128, "Software error code 128"),
+    (129, "Software error code 129"),
+    (130, "Offline"),
+    (141, "Grid overvoltage"),
+    (142, "Average grid overvoltage"),
+    (143, "Grid undervoltage"),
+    (144, "Grid overfrequency"),
+    (145, "Grid underfrequency"),
+    (146, "Rapid grid frequency change rate"),
+    (147, "Power grid outage"),
+    (148, "Grid disconnection"),
+    (149, "Island detected"),
+    (171, "Abnormal phase difference between phases"),
+    (181, "Abnormal insulation impedance"),
+    (182, "Abnormal grounding"),
+    (205, "MPPT-A: Input overvoltage"),
+    (206, "MPPT-B: Input overvoltage"),
+    (207, "MPPT-A: Input overcurrent"),
+    (208, "MPPT-B: Input overcurrent"),
+    (209, "PV-1: No input"),
+    (210, "PV-2: No input"),
+    (211, "PV-3: No input"),
+    (212, "PV-4: No input"),
+    (213, "MPPT-A: PV-1 & PV-2 abnormal wiring"),
+    (214, "MPPT-B: PV-3 & PV-4 abnormal wiring"),
+    (215, "PV-5: No input"),
+    (216, "PV-6: No input"),
+    (217, "MPPT-C: PV-5 & PV-6 abnormal wiring"),
+    (218, "MPPT-D: PV-7 & PV-8 abnormal wiring"),
+    (219, "MPPT-A: Input undervoltage"),
+    (220, "MPPT-B: Input undervoltage"),
+    (221, "MPPT-C: Input undervoltage"),
+    (222, "MPPT-D: Input undervoltage"),
+    (301, "Hardware error code 301"),
+    (302, "Hardware error code 302"),
+    (303, "Hardware error code 303"),
+    (304, "Hardware error code 304"),
+    (305, "Hardware error code 305"),
+    (306, "Hardware error code 306"),
+    (307, "Hardware error code 307"),
+    (308, "Hardware error code 308"),
+    (309, "Hardware error code 309"),
+    (310, "Hardware error code 310"),
+    (311, "Hardware error code 311"),
+    (312, "Hardware error code 312"),
+    (313, "Hardware error code 313"),
+    (314, "Hardware error code 314"),
+    (5041, "Error code-04 Port 1"),
+    (5042, "Error code-04 Port 2"),
+    (5043, "Error code-04 Port 3"),
+    (5044, "Error code-04 Port 4"),
+    (5051, "PV Input 1 Overvoltage/Undervoltage"),
+    (5052, "PV Input 2 Overvoltage/Undervoltage"),
+    (5053, "PV Input 3 Overvoltage/Undervoltage"),
+    (5054, "PV Input 4 Overvoltage/Undervoltage"),
+    (5060, "Abnormal bias"),
+    (5070, "Over temperature protection"),
+    (5080, "Grid Overvoltage/Undervoltage"),
+    (5090, "Grid Overfrequency/Underfrequency"),
+    (5100, "Island detected"),
+    (5120, "EEPROM reading and writing error"),
+    (5150, "10 min value grid overvoltage"),
+    (5200, "Firmware error"),
+    (8310, "Shut down"),
+];
+
+/// The human-readable description of an alarm code.
+pub fn alarm_text(code: i32) -> String {
+    ALARM_TEXTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or_else(
+            || format!("Unknown alarm code {code}"),
+            |(_, text)| text.to_string(),
+        )
+}
+
+impl Alarm {
+    /// Identifies the alarm in the log, which keeps it when the alarm ends and its end time is set.
+    pub fn key(&self) -> (String, i32, i32, i32) {
+        (
+            self.inverter_serial.clone(),
+            self.code,
+            self.number,
+            self.start_time,
+        )
+    }
+}
+
+impl From<&WInfoMO> for Alarm {
+    fn from(alarm: &WInfoMO) -> Self {
+        Self {
+            inverter_serial: serial_to_string(alarm.pv_sn),
+            code: alarm.w_code,
+            number: alarm.w_num,
+            text: alarm_text(alarm.w_code),
+            start_time: alarm.w_time1,
+            end_time: alarm.w_time2,
+        }
+    }
+}
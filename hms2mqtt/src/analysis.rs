@@ -0,0 +1,290 @@
+use crate::derived::PanelConfig;
+use crate::reading::{serial_to_string, PortState, Reading};
+use crate::sun::{sun_position, SunSchedule};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// a port is underperforming below this share of the power expected from its siblings
+static THRESHOLD_DEFAULT: f32 = 0.7;
+
+// how long a port has to stay below the threshold, in minutes
+static PERIOD_DEFAULT: i64 = 60;
+
+// below this share of their rated power, the ports differ too much by chance
+static MIN_RELATIVE_POWER: f32 = 0.05;
+
+// ports the sun shines on at a flat angle get mostly diffuse light, which the orientation does not tell
+static MIN_INCIDENCE: f64 = 0.2;
+
+// how much a single reading moves the learned ratio of a port to its siblings
+static BASELINE_WEIGHT: f32 = 0.001;
+
+/// `AnalysisConfig` tunes the detection of underperforming strings.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AnalysisConfig {
+    /// Share of the expected power below which a port is underperforming, 0.7 by default
+    pub threshold: Option<f32>,
+    /// Minutes a port has to stay below the threshold, 60 by default
+    pub period: Option<i64>,
+}
+
+/// `Underperformance` is raised when a port stayed below the power expected
+/// from its siblings and its own history for the configured period.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Underperformance {
+    pub inverter_serial: String,
+    pub port: i32,
+    /// When the port fell below the threshold
+    pub since: DateTime<Utc>,
+    /// Power of the port per its expected power at the time it was raised
+    pub ratio: f32,
+}
+
+#[derive(Clone, Debug)]
+struct PortHistory {
+    // the usual ratio of the normalised power of the port to that of its siblings
+    baseline: f32,
+    below_since: Option<DateTime<Utc>>,
+    raised: bool,
+}
+
+impl Default for PortHistory {
+    fn default() -> Self {
+        Self {
+            baseline: 1.,
+            below_since: None,
+            raised: false,
+        }
+    }
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    values.sort_by(f32::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.
+    } else {
+        values[middle]
+    }
+}
+
+/// `Analysis` compares the power of each port of a DTU with that of the other
+/// ports and with its own history, to find strings that underperform, e.g.
+/// because of a corroded connector or new shade.
+///
+/// The power of each port is normalised by the rated power of its panels and,
+/// if the location and the orientation of the panels are known, by the angle
+/// the sun shines on them. Only ports with configured panels are analysed,
+/// and at least two of them are needed.
+#[derive(Clone, Debug, Default)]
+pub struct Analysis {
+    config: AnalysisConfig,
+    panels: Vec<PanelConfig>,
+    location: Option<SunSchedule>,
+    ports: HashMap<(i64, i32), PortHistory>,
+}
+
+impl Analysis {
+    /// The panels are given per port, in the order the DTU reports the ports.
+    pub fn new(
+        config: AnalysisConfig,
+        panels: Vec<PanelConfig>,
+        location: Option<SunSchedule>,
+    ) -> Self {
+        Self {
+            config,
+            panels,
+            location,
+            ports: HashMap::new(),
+        }
+    }
+
+    // the power a port would deliver at the current position of the sun if its panels faced it
+    fn normalised_power(&self, i: usize, port: &PortState, time: DateTime<Utc>) -> Option<f32> {
+        let panel = self.panels.get(i).filter(|panel| panel.peak_power > 0.)?;
+        let incidence = match (self.location, panel.azimuth, panel.tilt) {
+            (Some(location), Some(azimuth), Some(tilt)) => {
+                let sun = sun_position(location.latitude, location.longitude, time);
+                let (elevation, tilt) = (sun.elevation.to_radians(), (tilt as f64).to_radians());
+                elevation.sin() * tilt.cos()
+                    + elevation.cos()
+                        * tilt.sin()
+                        * (sun.azimuth - azimuth as f64).to_radians().cos()
+            }
+            _ => 1.,
+        };
+        (incidence >= MIN_INCIDENCE).then(|| port.power / (panel.peak_power * incidence as f32))
+    }
+
+    /// Updates the history of the ports with the reading and marks the
+    /// underperforming ones in its metrics.
+    ///
+    /// Returns the ports that have just been found underperforming.
+    pub fn apply(&mut self, reading: &mut Reading) -> Vec<Underperformance> {
+        let state = &reading.state;
+        let analysed: Vec<usize> = (0..state.ports.len())
+            .filter(|i| {
+                self.panels
+                    .get(*i)
+                    .is_some_and(|panel| panel.peak_power > 0.)
+            })
+            .collect();
+        if reading.stale || analysed.len() < 2 {
+            return Vec::new();
+        }
+
+        let threshold = self.config.threshold.unwrap_or(THRESHOLD_DEFAULT);
+        let period = Duration::minutes(self.config.period.unwrap_or(PERIOD_DEFAULT));
+        let relative_power = analysed
+            .iter()
+            .map(|&i| state.ports[i].power / self.panels[i].peak_power)
+            .collect();
+        let normalised: Vec<(usize, f32)> = analysed
+            .iter()
+            .filter_map(|&i| Some((i, self.normalised_power(i, &state.ports[i], state.time)?)))
+            .collect();
+        let mut events = Vec::new();
+        // at dawn and dusk, or when too few ports face the sun, the histories are left as they are
+        if median(relative_power) >= MIN_RELATIVE_POWER && normalised.len() >= 2 {
+            for &(i, power) in &normalised {
+                let port = &state.ports[i];
+                let siblings = normalised
+                    .iter()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, power)| *power)
+                    .collect();
+                let reference = median(siblings);
+                if reference <= 0. {
+                    continue;
+                }
+                let history = self
+                    .ports
+                    .entry((port.inverter_serial, port.port))
+                    .or_default();
+                let ratio = power / reference / history.baseline;
+                if ratio >= threshold {
+                    history.baseline += BASELINE_WEIGHT * (power / reference - history.baseline);
+                    history.below_since = None;
+                    history.raised = false;
+                    continue;
+                }
+                let since = *history.below_since.get_or_insert(state.time);
+                if !history.raised && state.time - since >= period {
+                    history.raised = true;
+                    events.push(Underperformance {
+                        inverter_serial: serial_to_string(port.inverter_serial),
+                        port: port.port,
+                        since,
+                        ratio,
+                    });
+                }
+            }
+        }
+
+        for i in analysed {
+            let port = &reading.state.ports[i];
+            let raised = self
+                .ports
+                .get(&(port.inverter_serial, port.port))
+                .is_some_and(|history| history.raised);
+            if let Some(metrics) = reading.metrics.ports.get_mut(i) {
+                metrics.underperforming = Some(raised);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{noon, panels, StateBuilder};
+    use chrono::TimeZone;
+
+    #[test]
+    fn underperforming_port_is_raised_once() {
+        let config = AnalysisConfig {
+            threshold: Some(0.7),
+            period: Some(30),
+        };
+        let mut analysis = Analysis::new(config, panels(2), None);
+        let mut apply = |minutes, powers: [f32; 2]| {
+            let mut reading = StateBuilder::new(noon() + Duration::minutes(minutes))
+                .port(powers[0])
+                .port(powers[1])
+                .reading();
+            let events = analysis.apply(&mut reading);
+            (reading, events)
+        };
+
+        let (reading, events) = apply(0, [300., 300.]);
+        assert!(events.is_empty());
+        assert_eq!(reading.metrics.ports[1].underperforming, Some(false));
+
+        // the second port drops to half of the first, but not for long enough yet
+        assert!(apply(10, [300., 150.]).1.is_empty());
+        assert!(apply(30, [300., 150.]).1.is_empty());
+
+        let (reading, events) = apply(40, [300., 150.]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].inverter_serial, "114400000001");
+        assert_eq!(events[0].port, 2);
+        assert_eq!(events[0].since, noon() + Duration::minutes(10));
+        assert!((events[0].ratio - 0.5).abs() < 0.01);
+        assert_eq!(reading.metrics.ports[1].underperforming, Some(true));
+        // the first port has only one sibling, it is compared with the second one and does fine
+        assert_eq!(reading.metrics.ports[0].underperforming, Some(false));
+
+        // raised only once
+        assert!(apply(50, [300., 150.]).1.is_empty());
+
+        // at night, the ports are not compared and keep their state
+        let (reading, events) = apply(600, [0., 0.]);
+        assert!(events.is_empty());
+        assert_eq!(reading.metrics.ports[1].underperforming, Some(true));
+
+        let (reading, _) = apply(660, [300., 290.]);
+        assert_eq!(reading.metrics.ports[1].underperforming, Some(false));
+    }
+
+    #[test]
+    fn single_port_is_not_analysed() {
+        let mut analysis = Analysis::new(AnalysisConfig::default(), panels(1), None);
+        let mut reading = StateBuilder::new(noon()).port(300.).port(10.).reading();
+        assert!(analysis.apply(&mut reading).is_empty());
+        assert_eq!(reading.metrics.ports[1].underperforming, None);
+    }
+
+    #[test]
+    fn orientation_is_taken_into_account() {
+        let facing = |azimuth| PanelConfig {
+            peak_power: 400.,
+            azimuth: Some(azimuth),
+            tilt: Some(30.),
+        };
+        let config = AnalysisConfig {
+            threshold: Some(0.7),
+            period: Some(0),
+        };
+        let panels = vec![facing(90.), facing(270.)];
+        // in the morning in Berlin, a port facing east gets about three times the sun of one facing west
+        let morning = Utc.with_ymd_and_hms(2024, 6, 21, 8, 0, 0).unwrap();
+        let reading = || StateBuilder::new(morning).port(300.).port(100.).reading();
+
+        let location = SunSchedule::new(52.52, 13.405);
+        let mut oriented = Analysis::new(config.clone(), panels.clone(), Some(location));
+        let mut oriented_reading = reading();
+        assert!(oriented.apply(&mut oriented_reading).is_empty());
+        assert_eq!(
+            oriented_reading.metrics.ports[1].underperforming,
+            Some(false)
+        );
+
+        // without the location, the port facing west seems to underperform
+        let mut unoriented = Analysis::new(config, panels, None);
+        let events = unoriented.apply(&mut reading());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].port, 2);
+    }
+}
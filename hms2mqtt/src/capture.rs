@@ -0,0 +1,120 @@
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `Exchange` is a request sent to the DTU together with its response, as
+/// stored in a capture file, one JSON object per line.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Exchange {
+    /// Unix timestamp in milliseconds
+    pub time: u128,
+    /// The complete request frame, hex encoded
+    pub request: String,
+    /// The bytes received in response, hex encoded as they arrived including
+    /// truncated or garbled frames, or None if none arrived
+    pub response: Option<String>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid hex {hex:?}"));
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+/// `Recorder` appends the traffic of an inverter to a capture file.
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, request: &[u8], response: &[u8]) {
+        let exchange = Exchange {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            request: to_hex(request),
+            response: (!response.is_empty()).then(|| to_hex(response)),
+        };
+        let line = serde_json::to_string(&exchange).unwrap();
+        if let Err(e) = writeln!(self.file, "{line}") {
+            warn!("could not record exchange: {e}");
+        }
+    }
+}
+
+/// `Replay` answers requests from a capture file instead of a DTU.
+///
+/// Responses are handed out in the recorded order per command, so that the
+/// publisher emits what it did when the capture was taken.
+pub struct Replay {
+    responses: HashMap<[u8; 2], VecDeque<Option<Vec<u8>>>>,
+}
+
+impl Replay {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange = serde_json::from_str(&line)?;
+            let request = from_hex(&exchange.request)?;
+            let Some(command) = request.get(2..4) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request is shorter than a frame header",
+                ));
+            };
+            let response = exchange.response.as_deref().map(from_hex).transpose()?;
+            responses
+                .entry([command[0], command[1]])
+                .or_default()
+                .push_back(response);
+        }
+        Ok(Self { responses })
+    }
+
+    /// The bytes recorded in response to the next request with the same command.
+    ///
+    /// They are handed out unchanged, so truncated or garbled frames fail to
+    /// parse just like they did when the recording was taken. A DTU that did
+    /// not answer, e.g. because it closed the connection on a command the
+    /// firmware does not know, yields no bytes.
+    pub fn exchange(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        let end_of_capture = || {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no more responses in the capture",
+            )
+        };
+        let [_, _, c0, c1, ..] = request else {
+            return Err(end_of_capture());
+        };
+        let response = self
+            .responses
+            .get_mut(&[*c0, *c1])
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(end_of_capture)?;
+        Ok(response.unwrap_or_default())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::inverter::InverterError;
+use std::fmt;
+use std::str::FromStr;
+
+/// Inverters do not accept limits below 2% of their rated power.
+pub const MIN_POWER_LIMIT: f32 = 2.;
+
+/// `PowerLimit` is the active power limit of an inverter, either relative to
+/// its rated power or as absolute value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerLimit {
+    Percent(f32),
+    Watts(f32),
+}
+
+impl FromStr for PowerLimit {
+    type Err = String;
+
+    /// Parses `50`, `50%` and `50 %` as percentage and `400W` or `400 W` as watts.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (value, limit): (&str, fn(f32) -> PowerLimit) = if let Some(value) = s.strip_suffix('%')
+        {
+            (value, PowerLimit::Percent)
+        } else if let Some(value) = s.strip_suffix(['W', 'w']) {
+            (value, PowerLimit::Watts)
+        } else {
+            (s, PowerLimit::Percent)
+        };
+        value
+            .trim()
+            .parse::<f32>()
+            .map(limit)
+            .map_err(|e| format!("invalid power limit {s:?}: {e}"))
+    }
+}
+
+impl fmt::Display for PowerLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerLimit::Percent(percent) => write!(f, "{percent}%"),
+            PowerLimit::Watts(watts) => write!(f, "{watts}W"),
+        }
+    }
+}
+
+/// `InverterCommand` is an action that collectors can ask an inverter to perform.
+///
+/// Commands are addressed to a DTU and apply to all of its inverters.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InverterCommand {
+    SetPowerLimit(PowerLimit),
+    TurnOn,
+    TurnOff,
+    Restart,
+    RestartDtu,
+}
+
+impl InverterCommand {
+    /// The name of the command as used in MQTT topics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InverterCommand::SetPowerLimit(_) => "power_limit",
+            InverterCommand::TurnOn => "turn_on",
+            InverterCommand::TurnOff => "turn_off",
+            InverterCommand::Restart => "restart",
+            InverterCommand::RestartDtu => "restart_dtu",
+        }
+    }
+
+    /// Parses a command from its name and the payload of the command message.
+    pub fn parse(name: &str, payload: &str) -> Result<Self, String> {
+        match name {
+            "power_limit" => Ok(InverterCommand::SetPowerLimit(payload.parse()?)),
+            // the payload of the other commands carries no information
+            "turn_on" => Ok(InverterCommand::TurnOn),
+            "turn_off" => Ok(InverterCommand::TurnOff),
+            "restart" => Ok(InverterCommand::Restart),
+            "restart_dtu" => Ok(InverterCommand::RestartDtu),
+            _ => Err(format!("unknown command {name:?}")),
+        }
+    }
+}
+
+/// `CommandStatus` is the outcome of a command as seen by the publisher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandStatus {
+    Acknowledged,
+    Rejected,
+    TimedOut,
+}
+
+impl CommandStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandStatus::Acknowledged => "acknowledged",
+            CommandStatus::Rejected => "rejected",
+            CommandStatus::TimedOut => "timed_out",
+        }
+    }
+}
+
+impl<T> From<&Result<T, InverterError>> for CommandStatus {
+    fn from(result: &Result<T, InverterError>) -> Self {
+        match result {
+            Ok(_) => CommandStatus::Acknowledged,
+            // the DTU never answered, so it is unknown whether the command was executed
+            Err(InverterError::Connect(_) | InverterError::Io(_)) => CommandStatus::TimedOut,
+            Err(_) => CommandStatus::Rejected,
+        }
+    }
+}
+
+/// `CommandResponse` reports the outcome of a command back to the collectors.
+#[derive(Clone, Debug)]
+pub struct CommandResponse {
+    pub inverter: String,
+    /// The command as executed, e.g. with a power limit in watts converted to percent
+    pub command: InverterCommand,
+    pub status: CommandStatus,
+}
@@ -0,0 +1,215 @@
+use crate::reading::{DtuState, Reading};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::Deserialize;
+
+/// `PanelConfig` describes the modules connected to a single port.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct PanelConfig {
+    /// Rated power of the modules in Wp
+    pub peak_power: f32,
+    /// Direction the modules face, clockwise from north in degrees, i.e. 90° is east and 180° is south
+    pub azimuth: Option<f32>,
+    /// Angle of the modules to the horizontal in degrees
+    pub tilt: Option<f32>,
+}
+
+/// `PortMetrics` holds the values derived for a single PV input.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PortMetrics {
+    pub inverter_serial: i64,
+    pub port: i32,
+    /// Share of the port in the DC power of its inverter, [%]
+    pub dc_share: Option<f32>,
+    /// Yield of the day per installed power, [kWh/kWp], if the panels are configured
+    pub specific_yield: Option<f64>,
+    /// Energy that never decreases, set by `LifetimeEnergy`, [Wh]
+    pub lifetime_energy: Option<f64>,
+    /// The port stays below the power expected from it, set by `Analysis`
+    pub underperforming: Option<bool>,
+}
+
+/// `InverterMetrics` holds the values derived for a single inverter.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InverterMetrics {
+    pub serial: i64,
+    /// AC power per DC power of the ports, [%]
+    pub efficiency: Option<f32>,
+    /// Sum of the lifetime energy of its ports, [Wh]
+    pub lifetime_energy: Option<f64>,
+}
+
+/// `PeakPower` is the highest power of a day and when it was reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeakPower {
+    /// [W]
+    pub power: f32,
+    pub time: DateTime<Utc>,
+}
+
+/// `DerivedMetrics` are the values computed from a reading, the same for all outputs.
+///
+/// Values that cannot be computed, e.g. the efficiency while the ports do not
+/// deliver any power, are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DerivedMetrics {
+    /// AC power of the DTU per DC power of all ports, [%]
+    pub efficiency: Option<f32>,
+    /// Yield of the day per installed power of all ports, [kWh/kWp]
+    pub specific_yield: Option<f64>,
+    /// Peak of the AC power of the DTU on the day of the reading
+    pub peak_power: Option<PeakPower>,
+    pub inverters: Vec<InverterMetrics>,
+    pub ports: Vec<PortMetrics>,
+}
+
+fn percentage(part: f32, total: f32) -> Option<f32> {
+    (total > 0.).then(|| part / total * 100.)
+}
+
+/// `Derivation` computes the derived metrics of the readings of one DTU.
+///
+/// It keeps the peak power of the current day, so it has to see every
+/// reading of the DTU in order.
+#[derive(Clone, Debug, Default)]
+pub struct Derivation {
+    panels: Vec<PanelConfig>,
+    peak: Option<(NaiveDate, PeakPower)>,
+}
+
+impl Derivation {
+    /// The panels are given per port, in the order the DTU reports the ports.
+    pub fn new(panels: Vec<PanelConfig>) -> Self {
+        Self { panels, peak: None }
+    }
+
+    /// Computes the metrics of the reading and attaches them to it.
+    ///
+    /// Stale readings keep the metrics they have, they would only repeat the previous ones.
+    pub fn apply(&mut self, reading: &mut Reading) {
+        if !reading.stale {
+            reading.metrics = self.derive(&reading.state);
+        }
+    }
+
+    pub fn derive(&mut self, state: &DtuState) -> DerivedMetrics {
+        let dc_power = |serial: i64| -> f32 {
+            state
+                .ports
+                .iter()
+                .filter(|port| port.inverter_serial == serial)
+                .map(|port| port.power)
+                .sum()
+        };
+        let ports = state
+            .ports
+            .iter()
+            .enumerate()
+            .map(|(i, port)| PortMetrics {
+                inverter_serial: port.inverter_serial,
+                port: port.port,
+                dc_share: percentage(port.power, dc_power(port.inverter_serial)),
+                specific_yield: self
+                    .panels
+                    .get(i)
+                    .filter(|panel| panel.peak_power > 0.)
+                    .map(|panel| port.daily_yield / panel.peak_power as f64),
+                lifetime_energy: None,
+                underperforming: None,
+            })
+            .collect();
+        let inverters = state
+            .inverters
+            .iter()
+            .map(|inverter| InverterMetrics {
+                serial: inverter.serial,
+                efficiency: percentage(inverter.power, dc_power(inverter.serial)),
+                lifetime_energy: None,
+            })
+            .collect();
+
+        // the yield of ports without panels would distort the total
+        let peak_power: f32 = self
+            .panels
+            .iter()
+            .take(state.ports.len())
+            .map(|panel| panel.peak_power)
+            .sum();
+        let specific_yield = (self.panels.len() >= state.ports.len() && peak_power > 0.)
+            .then(|| state.daily_yield / peak_power as f64);
+
+        DerivedMetrics {
+            efficiency: percentage(state.power, state.ports.iter().map(|port| port.power).sum()),
+            specific_yield,
+            peak_power: Some(self.update_peak(state)),
+            inverters,
+            ports,
+        }
+    }
+
+    // the peak is reset when the local date of the readings changes
+    fn update_peak(&mut self, state: &DtuState) -> PeakPower {
+        let date = state.time.with_timezone(&Local).date_naive();
+        let current = PeakPower {
+            power: state.power,
+            time: state.time,
+        };
+        match &mut self.peak {
+            Some((peak_date, peak)) if *peak_date == date => {
+                if current.power > peak.power {
+                    *peak = current;
+                }
+            }
+            _ => self.peak = Some((date, current)),
+        }
+        self.peak.unwrap().1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{noon, panels, StateBuilder};
+    use chrono::Duration;
+
+    fn state(time: DateTime<Utc>, power: f32) -> DtuState {
+        StateBuilder::new(time)
+            .power(power)
+            .port(300.)
+            .energy(0., 800.)
+            .port(100.)
+            .energy(0., 400.)
+            .build()
+    }
+
+    #[test]
+    fn metrics_of_a_reading() {
+        let metrics = Derivation::new(panels(2)).derive(&state(noon(), 380.));
+        assert_eq!(metrics.efficiency, Some(95.));
+        assert_eq!(metrics.inverters[0].efficiency, Some(95.));
+        assert_eq!(metrics.ports[0].dc_share, Some(75.));
+        assert_eq!(metrics.ports[1].dc_share, Some(25.));
+        assert_eq!(metrics.ports[0].specific_yield, Some(2.));
+        assert_eq!(metrics.specific_yield, Some(1.5));
+
+        // ports without power have no share
+        let mut dark = state(noon(), 0.);
+        dark.ports.iter_mut().for_each(|port| port.power = 0.);
+        let metrics = Derivation::default().derive(&dark);
+        assert_eq!(metrics.efficiency, None);
+        assert_eq!(metrics.ports[0].dc_share, None);
+        assert_eq!(metrics.specific_yield, None);
+    }
+
+    #[test]
+    fn peak_power_of_the_day() {
+        let mut derivation = Derivation::default();
+        derivation.derive(&state(noon(), 380.));
+
+        // the peak is kept until a higher one, and starts over the next day
+        let metrics = derivation.derive(&state(noon() + Duration::hours(1), 200.));
+        let peak = metrics.peak_power.unwrap();
+        assert_eq!((peak.power, peak.time), (380., noon()));
+        let metrics = derivation.derive(&state(noon() + Duration::days(1), 100.));
+        assert_eq!(metrics.peak_power.unwrap().power, 100.);
+    }
+}
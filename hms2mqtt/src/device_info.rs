@@ -0,0 +1,122 @@
+use crate::protos::hoymiles::APPInformationData::{APPInfoDataResDTO, APPPvInfoMO};
+use crate::reading::serial_to_string;
+use serde::Serialize;
+
+/// `InverterInfo` identifies a single inverter connected to a DTU.
+#[derive(Clone, Debug, Serialize)]
+pub struct InverterInfo {
+    pub serial: String,
+    pub model: String,
+    pub sw_version: String,
+    pub hw_version: String,
+}
+
+/// `DeviceInfo` holds model and version information of a DTU and its inverters.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    pub dtu_sn: String,
+    pub dtu_sw_version: String,
+    pub dtu_hw_version: String,
+    pub inverters: Vec<InverterInfo>,
+}
+
+impl DeviceInfo {
+    /// The model of the (first) inverter, which names the whole device.
+    pub fn model(&self) -> Option<&str> {
+        self.inverters
+            .first()
+            .map(|inverter| inverter.model.as_str())
+    }
+
+    /// Names the model of a DTU with a single inverter after the rated power
+    /// configured for it, which the DTU does not report.
+    pub fn with_rated_power(mut self, rated_power: Option<f32>) -> Self {
+        if let [inverter] = self.inverters.as_mut_slice() {
+            inverter.model = model_from_serial(&inverter.serial, rated_power);
+        }
+        self
+    }
+}
+
+// The leading digits of the inverter serial number identify the series and
+// the number of ports. The power rating is neither part of the serial number
+// nor of the device information, only the configured rated power tells it.
+const MODELS: [(&str, &str, u8); 11] = [
+    ("1121", "HM", 1),
+    ("1141", "HM", 2),
+    ("1161", "HM", 4),
+    ("1122", "HM", 1),
+    ("1142", "HM", 2),
+    ("1162", "HM", 4),
+    ("1124", "HMS", 1),
+    ("1144", "HMS", 2),
+    ("1164", "HMS", 4),
+    ("1382", "HMT", 4),
+    ("1361", "HMT", 6),
+];
+
+// e.g. HMS-2T, or HMS-800W-2T if the rated power is known
+fn model_from_serial(serial: &str, rated_power: Option<f32>) -> String {
+    let Some((_, series, ports)) = MODELS
+        .iter()
+        .find(|(prefix, ..)| serial.starts_with(prefix))
+    else {
+        return "unknown".to_string();
+    };
+    match rated_power {
+        // only the HMS series carries the unit in its model names
+        Some(rated_power) if *series == "HMS" => format!("{series}-{rated_power}W-{ports}T"),
+        Some(rated_power) => format!("{series}-{rated_power}-{ports}T"),
+        None => format!("{series}-{ports}T"),
+    }
+}
+
+// inverter firmware is encoded in decimal digits, e.g. 10012 is V01.00.12
+fn inverter_version(version: i32) -> String {
+    format!(
+        "V{:02}.{:02}.{:02}",
+        version / 10000,
+        (version % 10000) / 100,
+        version % 100
+    )
+}
+
+// the DTU packs its version into nibbles, e.g. 0x1016 is V01.00.22
+fn dtu_version(version: i32) -> String {
+    format!(
+        "V{:02}.{:02}.{:02}",
+        (version >> 12) & 0xf,
+        (version >> 8) & 0xf,
+        version & 0xff
+    )
+}
+
+fn hardware_version(version: i32) -> String {
+    format!("H{:02}.{:02}", version >> 8, version & 0xff)
+}
+
+impl From<&APPPvInfoMO> for InverterInfo {
+    fn from(info: &APPPvInfoMO) -> Self {
+        let serial = serial_to_string(info.pv_sn);
+        Self {
+            model: model_from_serial(&serial, None),
+            serial,
+            sw_version: inverter_version(info.pv_sw_version),
+            hw_version: hardware_version(info.pv_hw_version),
+        }
+    }
+}
+
+impl From<&APPInfoDataResDTO> for DeviceInfo {
+    fn from(info: &APPInfoDataResDTO) -> Self {
+        let mut inverters: Vec<InverterInfo> = info.pv_info.iter().map(Into::into).collect();
+        // list each inverter only once, even if the DTU repeats entries
+        inverters.dedup_by(|a, b| a.serial == b.serial);
+        Self {
+            dtu_sn: info.dtu_sn.clone(),
+            dtu_sw_version: dtu_version(info.dtu_info.dtu_sw_version),
+            dtu_hw_version: hardware_version(info.dtu_info.dtu_hw_version),
+            inverters,
+        }
+    }
+}
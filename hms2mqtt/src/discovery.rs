@@ -0,0 +1,174 @@
+use crate::inverter::Inverter;
+use log::{debug, info};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+// the DTU answers on this port
+pub const DTU_PORT: u16 = 10081;
+
+// hosts on the LAN answer quickly, waiting longer would only slow down the scan
+static PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+
+// number of hosts that are probed at the same time
+static PROBE_BATCH_SIZE: usize = 64;
+
+// larger networks would take too long to scan host by host
+static MIN_PREFIX_LENGTH: u8 = 16;
+
+/// `Subnet` is an IPv4 network given in CIDR notation, e.g. `192.168.1.0/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix_length: u8,
+}
+
+impl Subnet {
+    pub fn new(address: Ipv4Addr, prefix_length: u8) -> Result<Self, String> {
+        if !(MIN_PREFIX_LENGTH..=32).contains(&prefix_length) {
+            return Err(format!(
+                "prefix length {prefix_length} is out of range {MIN_PREFIX_LENGTH}..=32"
+            ));
+        }
+        let mask = u32::MAX << (32 - prefix_length as u32);
+        Ok(Self {
+            network: Ipv4Addr::from(u32::from(address) & mask),
+            prefix_length,
+        })
+    }
+
+    /// The /24 network of the interface that routes to the internet.
+    ///
+    /// No packet is sent, connecting a UDP socket only selects the interface.
+    pub fn local() -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect("8.8.8.8:80")?;
+        match socket.local_addr()?.ip() {
+            IpAddr::V4(address) => Ok(Self::new(address, 24).unwrap()),
+            IpAddr::V6(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only IPv4 networks can be scanned",
+            )),
+        }
+    }
+
+    /// The addresses of all hosts, i.e. without network and broadcast address.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.network);
+        let size = 1_u32 << (32 - self.prefix_length as u32);
+        // point-to-point networks have no network and broadcast address
+        let range = if size <= 2 {
+            first..first + size
+        } else {
+            first + 1..first + size - 1
+        };
+        range.map(Ipv4Addr::from)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = s.split_once('/').unwrap_or((s, "32"));
+        let address = address
+            .trim()
+            .parse::<Ipv4Addr>()
+            .map_err(|e| format!("invalid subnet {s:?}: {e}"))?;
+        let prefix_length = prefix_length
+            .trim()
+            .parse::<u8>()
+            .map_err(|e| format!("invalid subnet {s:?}: {e}"))?;
+        Self::new(address, prefix_length)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+/// `DiscoveredDtu` is a DTU that answered the handshake during a scan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredDtu {
+    pub dtu_sn: String,
+    pub address: SocketAddr,
+}
+
+/// `Discovery` searches a subnet for DTUs.
+#[derive(Clone, Copy, Debug)]
+pub struct Discovery {
+    subnet: Subnet,
+    port: u16,
+}
+
+impl Discovery {
+    pub fn new(subnet: Subnet) -> Self {
+        Self {
+            subnet,
+            port: DTU_PORT,
+        }
+    }
+
+    /// Scans another port than the one of the DTU, e.g. that of a mock DTU.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Returns all DTUs in the subnet, ordered by address.
+    ///
+    /// Every host that accepts connections on the port has to identify itself
+    /// with the serial number of a DTU, other services are skipped.
+    pub fn scan(&self) -> Vec<DiscoveredDtu> {
+        info!("scanning {} for DTUs", self.subnet);
+        let addresses: Vec<SocketAddr> = self
+            .subnet
+            .hosts()
+            .map(|host| SocketAddr::new(IpAddr::V4(host), self.port))
+            .collect();
+
+        let mut open = Vec::new();
+        for batch in addresses.chunks(PROBE_BATCH_SIZE) {
+            let probes: Vec<_> = batch
+                .iter()
+                .map(|&address| {
+                    thread::spawn(move || {
+                        TcpStream::connect_timeout(&address, PROBE_TIMEOUT)
+                            .is_ok()
+                            .then_some(address)
+                    })
+                })
+                .collect();
+            open.extend(probes.into_iter().filter_map(|probe| probe.join().ok()?));
+        }
+
+        open.into_iter()
+            .filter_map(|address| {
+                let mut inverter = Inverter::new("", &address.to_string());
+                match inverter.get_device_info() {
+                    Ok(info) => Some(DiscoveredDtu {
+                        dtu_sn: info.dtu_sn,
+                        address,
+                    }),
+                    Err(e) => {
+                        debug!("{address} is no DTU: {e}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// The address of the DTU with the given serial number, if it is in the subnet.
+    pub fn locate(&self, dtu_sn: &str) -> Option<SocketAddr> {
+        self.scan()
+            .into_iter()
+            .find(|dtu| dtu.dtu_sn.eq_ignore_ascii_case(dtu_sn))
+            .map(|dtu| dtu.address)
+    }
+}
@@ -0,0 +1,87 @@
+use crate::protos::hoymiles::GetConfig::GetConfigResDTO;
+use serde::Serialize;
+
+/// `DtuConfig` holds the settings of a DTU that are worth auditing.
+///
+/// Credentials stored on the DTU, like the Wi-Fi password, are deliberately left out.
+#[derive(Clone, Debug, Serialize)]
+pub struct DtuConfig {
+    pub dtu_sn: String,
+    pub wifi_ssid: String,
+    pub wifi_rssi: i32,
+    pub wifi_ip_address: String,
+    pub dtu_ap_ssid: String,
+    pub mac_address: String,
+    pub dhcp: bool,
+    pub ip_address: String,
+    pub subnet_mask: String,
+    pub default_gateway: String,
+    pub server_domain_name: String,
+    pub server_port: i32,
+    pub server_send_time: i32,
+    pub access_model: i32,
+    pub inverter_type: i32,
+    pub netmode: i32,
+    pub sub1g_work_channel: i32,
+    pub limit_power_mypower: i32,
+    pub zero_export_enable: bool,
+}
+
+fn format_ip(octets: [i32; 4]) -> String {
+    octets.map(|octet| octet.to_string()).join(".")
+}
+
+impl From<&GetConfigResDTO> for DtuConfig {
+    fn from(config: &GetConfigResDTO) -> Self {
+        Self {
+            dtu_sn: config.dtu_sn.clone(),
+            wifi_ssid: config.wifi_ssid.clone(),
+            wifi_rssi: config.wifi_rssi,
+            wifi_ip_address: format_ip([
+                config.wifi_ip_addr_0,
+                config.wifi_ip_addr_1,
+                config.wifi_ip_addr_2,
+                config.wifi_ip_addr_3,
+            ]),
+            dtu_ap_ssid: config.dtu_ap_ssid.clone(),
+            mac_address: [
+                config.mac_0,
+                config.mac_1,
+                config.mac_2,
+                config.mac_3,
+                config.mac_4,
+                config.mac_5,
+            ]
+            .map(|octet| format!("{:02x}", octet as u8))
+            .join(":"),
+            dhcp: config.dhcp_switch != 0,
+            ip_address: format_ip([
+                config.ip_addr_0,
+                config.ip_addr_1,
+                config.ip_addr_2,
+                config.ip_addr_3,
+            ]),
+            subnet_mask: format_ip([
+                config.subnet_mask_0,
+                config.subnet_mask_1,
+                config.subnet_mask_2,
+                config.subnet_mask_3,
+            ]),
+            default_gateway: format_ip([
+                config.default_gateway_0,
+                config.default_gateway_1,
+                config.default_gateway_2,
+                config.default_gateway_3,
+            ]),
+            server_domain_name: config.server_domain_name.clone(),
+            server_port: config.serverport,
+            server_send_time: config.server_send_time,
+            access_model: config.access_model,
+            inverter_type: config.inv_type,
+            netmode: config.netmode_select,
+            sub1g_work_channel: config.sub1g_work_channel,
+            limit_power_mypower: config.limit_power_mypower,
+            zero_export_enable: config.zero_export_enable != 0,
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// Readings for the unit tests, built up from a DTU with a single inverter.
+use crate::derived::{Derivation, PanelConfig};
+use crate::reading::{DtuState, InverterState, PortState, Reading};
+use chrono::{DateTime, Local, TimeZone, Utc};
+
+pub(crate) const SERIAL: i64 = 0x1144_0000_0001;
+
+/// Local noon of the summer solstice, so the readings of a test stay on one local date.
+pub(crate) fn noon() -> DateTime<Utc> {
+    Local
+        .with_ymd_and_hms(2024, 6, 21, 12, 0, 0)
+        .unwrap()
+        .to_utc()
+}
+
+pub(crate) fn port(inverter_serial: i64, port: i32) -> PortState {
+    PortState {
+        inverter_serial,
+        port,
+        ..Default::default()
+    }
+}
+
+/// Panels of 400 Wp on each of the given number of ports.
+pub(crate) fn panels(count: usize) -> Vec<PanelConfig> {
+    vec![
+        PanelConfig {
+            peak_power: 400.,
+            ..Default::default()
+        };
+        count
+    ]
+}
+
+/// `StateBuilder` builds the state of a DTU with one inverter, ports are numbered as they are added.
+pub(crate) struct StateBuilder {
+    state: DtuState,
+}
+
+impl StateBuilder {
+    pub(crate) fn new(time: DateTime<Utc>) -> Self {
+        Self {
+            state: DtuState {
+                dtu_sn: "4143A0123456".to_string(),
+                time,
+                inverters: vec![InverterState {
+                    serial: SERIAL,
+                    number: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Sets the AC power of the DTU and its inverter.
+    pub(crate) fn power(mut self, power: f32) -> Self {
+        self.state.power = power;
+        self.state.inverters[0].power = power;
+        self
+    }
+
+    /// Adds a port with the given DC power.
+    pub(crate) fn port(mut self, power: f32) -> Self {
+        let number = self.state.ports.len() as i32 + 1;
+        self.state.ports.push(PortState {
+            power,
+            ..port(SERIAL, number)
+        });
+        self
+    }
+
+    /// Sets the energy of the port added last, its daily yield adds to that of the DTU.
+    pub(crate) fn energy(mut self, energy_total: f64, daily_yield: f64) -> Self {
+        let port = self.state.ports.last_mut().unwrap();
+        port.energy_total = energy_total;
+        port.daily_yield = daily_yield;
+        self.state.daily_yield += daily_yield;
+        self
+    }
+
+    pub(crate) fn build(self) -> DtuState {
+        self.state
+    }
+
+    /// A reading of the state with its derived metrics, as the later stages expect it.
+    pub(crate) fn reading(self) -> Reading {
+        let mut reading = Reading::new("", self.state);
+        Derivation::default().apply(&mut reading);
+        reading
+    }
+}
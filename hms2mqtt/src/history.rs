@@ -0,0 +1,43 @@
+use crate::protos::hoymiles::AppGetHistPower::AppGetHistPowerReqDTO;
+use crate::reading::serial_to_string;
+use serde::Serialize;
+
+/// `PowerSample` is a single point of the power curve.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PowerSample {
+    /// Unix timestamp in seconds
+    pub time: i64,
+    /// AC power in watts
+    pub power: f32,
+}
+
+/// `PowerHistory` is the power curve of the current day as recorded by the DTU.
+///
+/// It lets collectors that store time series fill the gaps of an outage.
+#[derive(Clone, Debug, Serialize)]
+pub struct PowerHistory {
+    pub inverter_serial: String,
+    /// Energy of the current day in Wh
+    pub daily_energy: i32,
+    pub samples: Vec<PowerSample>,
+}
+
+impl From<&AppGetHistPowerReqDTO> for PowerHistory {
+    fn from(history: &AppGetHistPowerReqDTO) -> Self {
+        let start_time = history.start_time as i64;
+        let step_time = history.step_time as i64;
+        Self {
+            inverter_serial: serial_to_string(history.serial_number),
+            daily_energy: history.daily_energy,
+            samples: history
+                .power_array
+                .iter()
+                .enumerate()
+                .map(|(i, power)| PowerSample {
+                    time: start_time + i as i64 * step_time,
+                    power: *power as f32 / 10.,
+                })
+                .collect(),
+        }
+    }
+}
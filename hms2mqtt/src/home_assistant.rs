@@ -1,34 +1,163 @@
-use crate::home_assistant_config::DeviceConfig;
-use crate::mqtt_wrapper::MqttWrapper;
-use crate::{mqtt_config::MqttConfig, protos::hoymiles::RealData::HMSStateResponse};
+use crate::alarm::Alarm;
+use crate::analysis::Underperformance;
+use crate::command::{
+    CommandResponse, CommandStatus, InverterCommand, PowerLimit, MIN_POWER_LIMIT,
+};
+use crate::derived::DerivedMetrics;
+use crate::device_info::DeviceInfo;
+use crate::dtu_config::DtuConfig;
+use crate::home_assistant_config::{
+    BinarySensorConfig, ButtonConfig, DeviceConfig, EventConfig, NumberConfig,
+};
+use crate::mqtt_config::MqttConfig;
+use crate::mqtt_wrapper::{MqttWrapper, QoS};
+use crate::reading::{
+    serial_to_string, DtuState, InverterState, PortState, Reading, LINE_NAMES, PHASE_NAMES,
+};
 
 use crate::home_assistant_config::SensorConfig;
 use crate::metric_collector::MetricCollector;
-use log::{debug, error};
+use log::{debug, error, warn};
 use serde_json::json;
+use std::collections::HashMap;
+
+// placeholder until the model has been read from the DTU
+static UNKNOWN_MODEL: &str = "HMS-WiFi";
 
 pub struct HomeAssistant<MQTT: MqttWrapper> {
     client: MQTT,
+    // model and versions of each DTU, keyed by its serial number
+    device_info: HashMap<String, DeviceInfo>,
+    // names of the configured inverters, keyed by the short DTU serial used in topics
+    inverters: HashMap<String, String>,
 }
 
 impl<MQTT: MqttWrapper> HomeAssistant<MQTT> {
     pub fn new(config: &MqttConfig) -> Self {
-        let client = MQTT::new(config, "-ha");
-        Self { client }
+        let mut client = MQTT::new(config, "-ha");
+        if let Err(e) = client.subscribe("solar/+/+/set", QoS::AtLeastOnce) {
+            warn!("subscription to command topics failed: {e}");
+        }
+        Self {
+            client,
+            device_info: HashMap::new(),
+            inverters: HashMap::new(),
+        }
+    }
+
+    fn device_config(&self, inverter: &str, dtu_sn: &str) -> DeviceConfig {
+        device_config(inverter, dtu_sn, self.device_info.get(dtu_sn))
     }
 
     fn publish_json(&mut self, topic: &str, payload: serde_json::Value) {
         debug!("Publishing to {topic} with payload {payload}");
 
         let payload = serde_json::to_string(&payload).unwrap();
-        if let Err(e) =
-            self.client
-                .publish(topic, crate::mqtt_wrapper::QoS::AtMostOnce, true, payload)
-        {
+        self.publish_raw(topic, payload);
+    }
+
+    fn publish_raw(&mut self, topic: &str, payload: String) {
+        if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
             error!("Failed to publish message: {e:?}");
         }
     }
 
+    fn publish_power_limit_config(&mut self, device_config: &DeviceConfig, short_dtu_sn: &str) {
+        let number_config = NumberConfig::percentage(
+            &format!("solar/hms_{short_dtu_sn}/power_limit/set"),
+            &format!("solar/hms_{short_dtu_sn}/power_limit"),
+            device_config,
+            "Power Limit",
+            "power_limit",
+            MIN_POWER_LIMIT,
+        );
+        let config_topic = format!(
+            "homeassistant/number/hms_{short_dtu_sn}/{}/config",
+            number_config.unique_id
+        );
+        self.publish_json(&config_topic, serde_json::to_value(number_config).unwrap());
+    }
+
+    fn publish_button_configs(&mut self, device_config: &DeviceConfig, short_dtu_sn: &str) {
+        // like the power limit, commands apply to all inverters of the DTU
+        let command_topic = |command: &str| format!("solar/hms_{short_dtu_sn}/{command}/set");
+        let button_configs = [
+            ButtonConfig::new(
+                &command_topic("turn_on"),
+                device_config,
+                "Turn On Inverters",
+                "turn_on",
+            ),
+            ButtonConfig::new(
+                &command_topic("turn_off"),
+                device_config,
+                "Turn Off Inverters",
+                "turn_off",
+            ),
+            ButtonConfig::restart(
+                &command_topic("restart"),
+                device_config,
+                "Restart Inverters",
+                "restart",
+            ),
+            ButtonConfig::restart(
+                &command_topic("restart_dtu"),
+                device_config,
+                "Restart DTU",
+                "restart_dtu",
+            ),
+        ];
+        for button_config in button_configs {
+            let config_topic = format!(
+                "homeassistant/button/hms_{short_dtu_sn}/{}/config",
+                button_config.unique_id
+            );
+            self.publish_json(&config_topic, serde_json::to_value(button_config).unwrap());
+        }
+    }
+
+    fn publish_alarm_config(&mut self, device_config: &DeviceConfig, short_dtu_sn: &str) {
+        let event_config = EventConfig::new(
+            &format!("solar/hms_{short_dtu_sn}/alarm"),
+            device_config,
+            "Alarm",
+            "alarm",
+            &["alarm"],
+        );
+        let config_topic = format!(
+            "homeassistant/event/hms_{short_dtu_sn}/{}/config",
+            event_config.unique_id
+        );
+        self.publish_json(&config_topic, serde_json::to_value(event_config).unwrap());
+    }
+
+    fn publish_underperformance_config(
+        &mut self,
+        device_config: &DeviceConfig,
+        short_dtu_sn: &str,
+    ) {
+        let event_config = EventConfig::new(
+            &format!("solar/hms_{short_dtu_sn}/underperformance"),
+            device_config,
+            "String Underperforming",
+            "underperformance",
+            &["underperforming"],
+        );
+        let config_topic = format!(
+            "homeassistant/event/hms_{short_dtu_sn}/{}/config",
+            event_config.unique_id
+        );
+        self.publish_json(&config_topic, serde_json::to_value(event_config).unwrap());
+    }
+
+    // commands and alarms only name the inverter, the topics use the serial of its DTU
+    fn short_dtu_sn_of(&self, inverter: &str) -> Option<String> {
+        self.inverters
+            .iter()
+            .find(|(_, name)| *name == inverter)
+            .map(|(short_dtu_sn, _)| short_dtu_sn.clone())
+    }
+
     fn publish_configs(&mut self, config_topic: &str, sensor_configs: &Vec<SensorConfig>) {
         // configs let home assistant know what sensors are available and where to find them
         for sensor_config in sensor_configs {
@@ -38,181 +167,762 @@ impl<MQTT: MqttWrapper> HomeAssistant<MQTT> {
         }
     }
 
-    fn publish_states(&mut self, hms_state: &HMSStateResponse, state_topic: &str) {
+    fn publish_status_configs(
+        &mut self,
+        hms_state: &DtuState,
+        metrics: &DerivedMetrics,
+        device_config: &DeviceConfig,
+        short_dtu_sn: &str,
+        state_topic: &str,
+    ) {
+        // readings that failed a plausibility check with policy flag
+        let implausible = BinarySensorConfig::new(
+            state_topic,
+            device_config,
+            "Implausible Reading",
+            "implausible",
+            Some("problem"),
+        )
+        .diagnostic();
+        self.publish_json(
+            &format!(
+                "homeassistant/binary_sensor/hms_{short_dtu_sn}/{}/config",
+                implausible.unique_id
+            ),
+            serde_json::to_value(implausible).unwrap(),
+        );
+        // only ports that are analysed get an entity
+        for (port, port_metrics) in hms_state.ports.iter().zip(&metrics.ports) {
+            if port_metrics.underperforming.is_none() {
+                continue;
+            }
+            let (key, name) = hms_state.port_key_and_name(port);
+            let config = BinarySensorConfig::new(
+                state_topic,
+                device_config,
+                &format!("{name} Underperforming"),
+                &format!("{key}_underperforming"),
+                Some("problem"),
+            );
+            self.publish_json(
+                &format!(
+                    "homeassistant/binary_sensor/hms_{short_dtu_sn}/{}/config",
+                    config.unique_id
+                ),
+                serde_json::to_value(config).unwrap(),
+            );
+        }
+        for inverter in &hms_state.inverters {
+            let idx = hms_state.inverter_id(inverter);
+            let status = &inverter.status;
+            let mut configs = vec![BinarySensorConfig::new(
+                state_topic,
+                device_config,
+                &format!("Inverter {} Producing", idx),
+                &format!("inv_{}_producing", idx),
+                Some("running"),
+            )];
+            // flags the firmware does not report get no entity
+            if status.link.is_some() {
+                configs.push(
+                    BinarySensorConfig::new(
+                        state_topic,
+                        device_config,
+                        &format!("Inverter {} Link", idx),
+                        &format!("inv_{}_link", idx),
+                        Some("connectivity"),
+                    )
+                    .diagnostic(),
+                );
+            }
+            if status.limited.is_some() {
+                configs.push(BinarySensorConfig::new(
+                    state_topic,
+                    device_config,
+                    &format!("Inverter {} Limited", idx),
+                    &format!("inv_{}_limited", idx),
+                    None,
+                ));
+            }
+            if status.alarm_active.is_some() {
+                configs.push(BinarySensorConfig::new(
+                    state_topic,
+                    device_config,
+                    &format!("Inverter {} Alarm", idx),
+                    &format!("inv_{}_alarm_active", idx),
+                    Some("problem"),
+                ));
+            }
+            for config in configs {
+                let config_topic = format!(
+                    "homeassistant/binary_sensor/hms_{short_dtu_sn}/{}/config",
+                    config.unique_id
+                );
+                self.publish_json(&config_topic, serde_json::to_value(config).unwrap());
+            }
+        }
+    }
+
+    fn publish_states(&mut self, reading: &Reading, state_topic: &str) {
         // states contain the actual data
-        let json_payload = hms_state.to_json_payload();
+        let mut json_payload = reading.state.to_json_payload(&reading.metrics);
+        json_payload["implausible"] = if reading.violations.is_empty() {
+            "OFF"
+        } else {
+            "ON"
+        }
+        .into();
         self.publish_json(state_topic, json_payload);
     }
 }
 
 impl<MQTT: MqttWrapper> MetricCollector for HomeAssistant<MQTT> {
-    fn publish(&mut self, hms_state: &HMSStateResponse) {
+    fn publish(&mut self, reading: &Reading) {
+        // Home Assistant keeps the previous state, repeating it would only fake an update
+        if reading.stale {
+            return;
+        }
+        let hms_state = &reading.state;
         let config_topic = format!("homeassistant/sensor/hms_{}", hms_state.short_dtu_sn());
         let state_topic = format!("solar/hms_{}/state", hms_state.short_dtu_sn());
 
-        let device_config = hms_state.create_sensor_configs(&state_topic);
+        let device_config = self.device_config(&reading.inverter, &hms_state.dtu_sn);
+        let sensor_configs =
+            hms_state.create_sensor_configs(&reading.metrics, &device_config, &state_topic);
+
+        self.publish_configs(&config_topic, &sensor_configs);
+        let short_dtu_sn = hms_state.short_dtu_sn();
+        self.publish_status_configs(
+            hms_state,
+            &reading.metrics,
+            &device_config,
+            &short_dtu_sn,
+            &state_topic,
+        );
+        self.publish_states(reading, &state_topic);
+        // fields of unknown meaning, kept apart from the entities
+        self.publish_json(
+            &format!("solar/hms_{short_dtu_sn}/raw"),
+            hms_state.unknown_fields(),
+        );
 
-        self.publish_configs(&config_topic, &device_config);
-        self.publish_states(hms_state, &state_topic);
+        self.inverters
+            .insert(short_dtu_sn.clone(), reading.inverter.clone());
+        if !hms_state.inverters.is_empty() {
+            self.publish_power_limit_config(&device_config, &short_dtu_sn);
+            self.publish_button_configs(&device_config, &short_dtu_sn);
+            self.publish_alarm_config(&device_config, &short_dtu_sn);
+            self.publish_underperformance_config(&device_config, &short_dtu_sn);
+            // the number sets the limit of the whole DTU, the limits of its inverters are sensors
+            if let Some(power_limit) = hms_state.power_limit() {
+                self.publish_raw(
+                    &format!("solar/hms_{short_dtu_sn}/power_limit"),
+                    format!("{:.1}", power_limit),
+                );
+            }
+        }
     }
-}
 
-/// `HMSStateResponse` is a struct that contains the data from the inverter.
-///
-/// Provide utility functions to extract data from the struct.
-impl HMSStateResponse {
-    fn get_model(&self) -> String {
-        // TODO: figure out a way to properly identify the model
-        "HMS-WiFi".to_string()
+    fn publish_config(&mut self, inverter: &str, config: &DtuConfig) {
+        let short_dtu_sn = short_dtu_sn(&config.dtu_sn);
+        let config_topic = format!("homeassistant/sensor/hms_{short_dtu_sn}");
+        let state_topic = format!("solar/hms_{short_dtu_sn}/config");
+
+        let device_config = self.device_config(inverter, &config.dtu_sn);
+        let sensor_configs = create_config_sensor_configs(&device_config, &state_topic);
+
+        self.publish_configs(&config_topic, &sensor_configs);
+        self.publish_json(&state_topic, serde_json::to_value(config).unwrap());
+    }
+
+    fn publish_device_info(&mut self, inverter: &str, info: &DeviceInfo) {
+        self.device_info.insert(info.dtu_sn.clone(), info.clone());
+
+        let short_dtu_sn = short_dtu_sn(&info.dtu_sn);
+        let config_topic = format!("homeassistant/sensor/hms_{short_dtu_sn}");
+        let state_topic = format!("solar/hms_{short_dtu_sn}/info");
+
+        let device_config = self.device_config(inverter, &info.dtu_sn);
+        let mut sensor_configs = Vec::from([
+            SensorConfig::string(
+                &state_topic,
+                &device_config,
+                "DTU Firmware",
+                "dtu_sw_version",
+            )
+            .diagnostic(),
+            SensorConfig::string(
+                &state_topic,
+                &device_config,
+                "DTU Hardware",
+                "dtu_hw_version",
+            )
+            .diagnostic(),
+        ]);
+        let mut json = json!({
+            "dtu_sw_version": info.dtu_sw_version,
+            "dtu_hw_version": info.dtu_hw_version,
+        });
+        for inverter in &info.inverters {
+            let serial = &inverter.serial;
+            sensor_configs.extend([
+                SensorConfig::string(
+                    &state_topic,
+                    &device_config,
+                    &format!("Inverter {serial} Firmware"),
+                    &format!("inv_{serial}_sw_version"),
+                )
+                .diagnostic(),
+                SensorConfig::string(
+                    &state_topic,
+                    &device_config,
+                    &format!("Inverter {serial} Hardware"),
+                    &format!("inv_{serial}_hw_version"),
+                )
+                .diagnostic(),
+            ]);
+            json[format!("inv_{serial}_sw_version")] = inverter.sw_version.clone().into();
+            json[format!("inv_{serial}_hw_version")] = inverter.hw_version.clone().into();
+        }
+
+        self.publish_configs(&config_topic, &sensor_configs);
+        self.publish_json(&state_topic, json);
     }
 
-    fn get_name(&self) -> String {
-        format!("Hoymiles {} {}", self.get_model(), self.short_dtu_sn())
+    fn poll_commands(&mut self) -> Vec<(String, InverterCommand)> {
+        let mut commands = Vec::new();
+        while let Some((topic, payload)) = self.client.try_receive() {
+            // topics are of the form solar/hms_<short dtu serial>/<command>/set
+            let Some((short_dtu_sn, command)) = topic
+                .strip_prefix("solar/hms_")
+                .and_then(|path| path.strip_suffix("/set"))
+                .and_then(|path| path.split_once('/'))
+            else {
+                continue;
+            };
+            let Some(inverter) = self.inverters.get(short_dtu_sn) else {
+                warn!("ignoring command for unknown DTU {short_dtu_sn}");
+                continue;
+            };
+            let payload = String::from_utf8_lossy(&payload);
+            match InverterCommand::parse(command, &payload) {
+                Ok(command) => commands.push((inverter.clone(), command)),
+                Err(e) => warn!("ignoring message on command topic {topic}: {e}"),
+            }
+        }
+        commands
     }
 
+    fn publish_alarm(&mut self, inverter: &str, alarm: &Alarm) {
+        let Some(short_dtu_sn) = self.short_dtu_sn_of(inverter) else {
+            return;
+        };
+        let mut payload = serde_json::to_value(alarm).unwrap();
+        payload["event_type"] = "alarm".into();
+        let topic = format!("solar/hms_{short_dtu_sn}/alarm");
+        debug!("Publishing to {topic} with payload {payload}");
+        // events must not be retained, or they would fire again on every restart of Home Assistant
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+        {
+            error!("Failed to publish message: {e:?}");
+        }
+    }
+
+    fn publish_underperformance(&mut self, inverter: &str, event: &Underperformance) {
+        let Some(short_dtu_sn) = self.short_dtu_sn_of(inverter) else {
+            return;
+        };
+        let mut payload = serde_json::to_value(event).unwrap();
+        payload["event_type"] = "underperforming".into();
+        let topic = format!("solar/hms_{short_dtu_sn}/underperformance");
+        debug!("Publishing to {topic} with payload {payload}");
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+        {
+            error!("Failed to publish message: {e:?}");
+        }
+    }
+
+    fn publish_command_response(&mut self, response: &CommandResponse) {
+        let Some(short_dtu_sn) = self.short_dtu_sn_of(&response.inverter) else {
+            return;
+        };
+        let command = &response.command;
+        let status = response.status;
+        if status != CommandStatus::Acknowledged {
+            warn!(
+                "command {command:?} for DTU {short_dtu_sn} {}",
+                status.as_str()
+            );
+        }
+        if let (
+            InverterCommand::SetPowerLimit(PowerLimit::Percent(percent)),
+            CommandStatus::Acknowledged,
+        ) = (command, status)
+        {
+            self.publish_raw(
+                &format!("solar/hms_{short_dtu_sn}/power_limit"),
+                format!("{percent:.1}"),
+            );
+        }
+        // responses are events rather than state, so they are not retained
+        let topic = format!("solar/hms_{short_dtu_sn}/{}/response", command.name());
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, false, status.as_str())
+        {
+            error!("Failed to publish message: {e:?}");
+        }
+    }
+}
+
+fn short_dtu_sn(dtu_sn: &str) -> String {
+    dtu_sn.chars().take(8).collect()
+}
+
+fn device_config(inverter: &str, dtu_sn: &str, info: Option<&DeviceInfo>) -> DeviceConfig {
+    // until the device info has been fetched, the model is unknown
+    let model = info.and_then(|info| info.model()).unwrap_or(UNKNOWN_MODEL);
+    let name = if inverter.is_empty() {
+        format!("Hoymiles {} {}", model, short_dtu_sn(dtu_sn))
+    } else {
+        format!("Hoymiles {} {}", model, inverter)
+    };
+    let device_config = DeviceConfig::new(
+        name,
+        model.to_string(),
+        Vec::from([format!("hms_{}", short_dtu_sn(dtu_sn))]),
+    );
+    match info.and_then(|info| info.inverters.first().map(|inverter| (info, inverter))) {
+        Some((info, inverter)) => device_config.with_versions(
+            format!("{} (DTU {})", inverter.sw_version, info.dtu_sw_version),
+            format!("{} (DTU {})", inverter.hw_version, info.dtu_hw_version),
+        ),
+        None => device_config,
+    }
+}
+
+/// `DtuConfig` is published as a set of diagnostic sensors next to the readings.
+fn create_config_sensor_configs(
+    device_config: &DeviceConfig,
+    state_topic: &str,
+) -> Vec<SensorConfig> {
+    Vec::from([
+        SensorConfig::string(state_topic, device_config, "Wi-Fi SSID", "wifi_ssid").diagnostic(),
+        SensorConfig::signal_strength(state_topic, device_config, "Wi-Fi Signal", "wifi_rssi")
+            .diagnostic(),
+        SensorConfig::string(
+            state_topic,
+            device_config,
+            "Wi-Fi IP Address",
+            "wifi_ip_address",
+        )
+        .diagnostic(),
+        SensorConfig::string(state_topic, device_config, "MAC Address", "mac_address").diagnostic(),
+        SensorConfig::string(state_topic, device_config, "DHCP", "dhcp").diagnostic(),
+        SensorConfig::string(state_topic, device_config, "IP Address", "ip_address").diagnostic(),
+        SensorConfig::string(state_topic, device_config, "Subnet Mask", "subnet_mask").diagnostic(),
+        SensorConfig::string(
+            state_topic,
+            device_config,
+            "Default Gateway",
+            "default_gateway",
+        )
+        .diagnostic(),
+        SensorConfig::string(
+            state_topic,
+            device_config,
+            "Server Address",
+            "server_domain_name",
+        )
+        .diagnostic(),
+        SensorConfig::string(state_topic, device_config, "Server Port", "server_port").diagnostic(),
+        SensorConfig::string(
+            state_topic,
+            device_config,
+            "Server Send Time",
+            "server_send_time",
+        )
+        .diagnostic(),
+        SensorConfig::string(
+            state_topic,
+            device_config,
+            "Export Limit",
+            "limit_power_mypower",
+        )
+        .diagnostic(),
+        SensorConfig::string(
+            state_topic,
+            device_config,
+            "Zero Export",
+            "zero_export_enable",
+        )
+        .diagnostic(),
+    ])
+}
+
+/// `DtuState` is a struct that contains the data from the inverter.
+///
+/// Provide utility functions to extract data from the struct.
+impl DtuState {
     fn short_dtu_sn(&self) -> String {
-        self.dtu_sn[..8].to_string()
+        short_dtu_sn(&self.dtu_sn)
     }
 
-    fn get_total_efficiency(&self) -> f32 {
-        let total_module_power: f32 = self
-            .port_state
-            .iter()
-            .map(|port| port.pv_power as f32)
-            .sum();
-        if total_module_power > 0.0 {
-            self.pv_current_power as f32 / total_module_power * 100.0
+    // Inverters are numbered as long as the DTU has only one, which keeps the
+    // keys of existing setups. Otherwise their serial numbers tell them apart.
+    fn inverter_id(&self, inverter: &InverterState) -> String {
+        if self.has_several_inverters() {
+            serial_to_string(inverter.serial)
         } else {
-            0.0
+            inverter.number.to_string()
         }
     }
 
-    fn to_json_payload(&self) -> serde_json::Value {
+    // key and name of a port, grouped below its inverter on DTUs with several ones
+    fn port_key_and_name(&self, port: &PortState) -> (String, String) {
+        if self.has_several_inverters() {
+            let serial = serial_to_string(port.inverter_serial);
+            (
+                format!("inv_{serial}_pv_{}", port.port),
+                format!("Inverter {serial} PV {}", port.port),
+            )
+        } else {
+            (format!("pv_{}", port.port), format!("PV {}", port.port))
+        }
+    }
+
+    fn to_json_payload(&self, metrics: &DerivedMetrics) -> serde_json::Value {
         // when modifying this function, modify the sensor config in create_device_config accordingly
         let mut json = json!({
             "dtu_sn": self.dtu_sn,
-            "pv_current_power": format!("{:.2}", self.pv_current_power as f32 * 0.1),
-            "pv_daily_yield": self.pv_daily_yield,
-            "efficiency": format!("{:.2}", self.get_total_efficiency())
+            "pv_current_power": format!("{:.2}", self.power),
+            "pv_daily_yield": self.daily_yield,
+            "efficiency": format!("{:.2}", metrics.efficiency.unwrap_or_default())
         });
+        if let Some(specific_yield) = metrics.specific_yield {
+            json["specific_yield"] = format!("{:.3}", specific_yield).into();
+        }
+        if let Some(peak) = metrics.peak_power {
+            json["peak_power"] = format!("{:.2}", peak.power).into();
+            json["peak_power_time"] = peak.time.to_rfc3339().into();
+        }
 
         // Convert each PortState to json
-        for port in self.port_state.iter() {
-            json[format!("pv_{}_vol", port.pv_port)] =
-                format!("{:.2}", port.pv_vol as f32 * 0.1).into();
-            json[format!("pv_{}_cur", port.pv_port)] =
-                format!("{:.2}", port.pv_cur as f32 * 0.01).into();
-            json[format!("pv_{}_power", port.pv_port)] =
-                format!("{:.2}", port.pv_power as f32 * 0.1).into();
-            json[format!("pv_{}_energy_total", port.pv_port)] = port.pv_energy_total.into();
-            json[format!("pv_{}_daily_yield", port.pv_port)] = port.pv_daily_yield.into();
+        for (i, port) in self.ports.iter().enumerate() {
+            let (key, _) = self.port_key_and_name(port);
+            json[format!("{key}_vol")] = format!("{:.2}", port.voltage).into();
+            json[format!("{key}_cur")] = format!("{:.2}", port.current).into();
+            json[format!("{key}_power")] = format!("{:.2}", port.power).into();
+            json[format!("{key}_energy_total")] = port.energy_total.into();
+            json[format!("{key}_daily_yield")] = port.daily_yield.into();
+            if let Some(port_metrics) = metrics.ports.get(i) {
+                json[format!("{key}_dc_share")] =
+                    format!("{:.2}", port_metrics.dc_share.unwrap_or_default()).into();
+                if let Some(specific_yield) = port_metrics.specific_yield {
+                    json[format!("{key}_specific_yield")] = format!("{:.3}", specific_yield).into();
+                }
+                if let Some(lifetime_energy) = port_metrics.lifetime_energy {
+                    json[format!("{key}_lifetime_energy")] = lifetime_energy.into();
+                }
+                if let Some(underperforming) = port_metrics.underperforming {
+                    json[format!("{key}_underperforming")] =
+                        if underperforming { "ON" } else { "OFF" }.into();
+                }
+            }
         }
         // Convert each InverterState to json (for a HMS-XXXW-2T, there is only one inverter)
-        for inverter in self.inverter_state.iter() {
-            json[format!("inv_{}_grid_voltage", inverter.port_id)] =
-                format!("{:.2}", inverter.grid_voltage as f32 * 0.1).into();
-            json[format!("inv_{}_grid_freq", inverter.port_id)] =
-                format!("{:.2}", inverter.grid_freq as f32 * 0.01).into();
-            json[format!("inv_{}_pv_current_power", inverter.port_id)] =
-                format!("{:.2}", inverter.pv_current_power as f32 * 0.1).into();
-            json[format!("inv_{}_temperature", inverter.port_id)] =
-                format!("{:.2}", inverter.temperature as f32 * 0.1).into();
+        for (i, inverter) in self.inverters.iter().enumerate() {
+            let id = self.inverter_id(inverter);
+            if let Some(inverter_metrics) = metrics.inverters.get(i) {
+                json[format!("inv_{}_efficiency", id)] =
+                    format!("{:.2}", inverter_metrics.efficiency.unwrap_or_default()).into();
+                if let Some(lifetime_energy) = inverter_metrics.lifetime_energy {
+                    json[format!("inv_{}_lifetime_energy", id)] = lifetime_energy.into();
+                }
+            }
+            json[format!("inv_{}_grid_voltage", id)] =
+                format!("{:.2}", inverter.grid_voltage).into();
+            json[format!("inv_{}_grid_freq", id)] =
+                format!("{:.2}", inverter.grid_frequency).into();
+            json[format!("inv_{}_pv_current_power", id)] = format!("{:.2}", inverter.power).into();
+            json[format!("inv_{}_temperature", id)] = format!("{:.2}", inverter.temperature).into();
+            if let Some(power_limit) = inverter.power_limit {
+                json[format!("inv_{}_power_limit", id)] = format!("{:.1}", power_limit).into();
+            }
+            if let Some(reactive_power) = inverter.reactive_power {
+                json[format!("inv_{}_reactive_power", id)] =
+                    format!("{:.2}", reactive_power).into();
+            }
+            if let Some(power_factor) = inverter.power_factor {
+                json[format!("inv_{}_power_factor", id)] = format!("{:.3}", power_factor).into();
+            }
+            let status = &inverter.status;
+            let flags = [
+                ("producing", Some(status.producing)),
+                ("link", status.link),
+                ("limited", status.limited),
+                ("alarm_active", status.alarm_active),
+            ];
+            for (flag, value) in flags {
+                if let Some(value) = value {
+                    json[format!("inv_{id}_{flag}")] = if value { "ON" } else { "OFF" }.into();
+                }
+            }
+            // three-phase inverters only, single-phase ones keep the keys above
+            for ((phase, line), state) in PHASE_NAMES.iter().zip(LINE_NAMES).zip(&inverter.phases) {
+                json[format!("inv_{}_phase_{phase}_voltage", id)] =
+                    format!("{:.2}", state.voltage).into();
+                json[format!("inv_{}_phase_{phase}_current", id)] =
+                    format!("{:.2}", state.current).into();
+                json[format!("inv_{}_phase_{phase}_power", id)] =
+                    format!("{:.2}", state.power).into();
+                json[format!("inv_{}_line_{line}_voltage", id)] =
+                    format!("{:.2}", state.line_voltage).into();
+            }
+        }
+        for (i, meter) in self.meters.iter().enumerate() {
+            json[format!("meter_{}_power", i + 1)] = format!("{:.2}", meter.power).into();
+            json[format!("meter_{}_energy_exported", i + 1)] = meter.energy_exported.into();
+            json[format!("meter_{}_energy_imported", i + 1)] = meter.energy_imported.into();
         }
 
         json
     }
 
-    fn create_sensor_configs(&self, state_topic: &str) -> Vec<SensorConfig> {
+    fn create_sensor_configs(
+        &self,
+        metrics: &DerivedMetrics,
+        device_config: &DeviceConfig,
+        state_topic: &str,
+    ) -> Vec<SensorConfig> {
         let mut sensors = Vec::new();
 
-        let device_config = DeviceConfig::new(
-            self.get_name(),
-            self.get_model(),
-            Vec::from([format!("hms_{}", self.short_dtu_sn())]),
-        );
-
         // Sensors for the whole inverter
         sensors.extend([
-            SensorConfig::string(state_topic, &device_config, "DTU Serial Number", "dtu_sn"),
+            SensorConfig::string(state_topic, device_config, "DTU Serial Number", "dtu_sn"),
             SensorConfig::power(
                 state_topic,
-                &device_config,
+                device_config,
                 "Total Power",
                 "pv_current_power",
             ),
             SensorConfig::energy(
                 state_topic,
-                &device_config,
+                device_config,
                 "Total Daily Yield",
                 "pv_daily_yield",
             ),
-            SensorConfig::efficiency(state_topic, &device_config, "Efficiency", "efficiency"),
+            SensorConfig::efficiency(state_topic, device_config, "Efficiency", "efficiency"),
         ]);
+        if metrics.specific_yield.is_some() {
+            sensors.push(SensorConfig::specific_yield(
+                state_topic,
+                device_config,
+                "Specific Yield",
+                "specific_yield",
+            ));
+        }
+        if metrics.peak_power.is_some() {
+            sensors.extend([
+                SensorConfig::power(state_topic, device_config, "Peak Power", "peak_power"),
+                SensorConfig::timestamp(
+                    state_topic,
+                    device_config,
+                    "Peak Power Time",
+                    "peak_power_time",
+                ),
+            ]);
+        }
 
         // Sensors for each pv string
-        for port in &self.port_state {
-            let idx = port.pv_port;
+        for (i, port) in self.ports.iter().enumerate() {
+            let (key, name) = self.port_key_and_name(port);
+            if let Some(port_metrics) = metrics.ports.get(i) {
+                sensors.push(SensorConfig::percentage(
+                    state_topic,
+                    device_config,
+                    &format!("{name} DC Share"),
+                    &format!("{key}_dc_share"),
+                ));
+                if port_metrics.specific_yield.is_some() {
+                    sensors.push(SensorConfig::specific_yield(
+                        state_topic,
+                        device_config,
+                        &format!("{name} Specific Yield"),
+                        &format!("{key}_specific_yield"),
+                    ));
+                }
+                if port_metrics.lifetime_energy.is_some() {
+                    sensors.push(SensorConfig::energy(
+                        state_topic,
+                        device_config,
+                        &format!("{name} Lifetime Energy"),
+                        &format!("{key}_lifetime_energy"),
+                    ));
+                }
+            }
             sensors.extend([
                 SensorConfig::power(
                     state_topic,
-                    &device_config,
-                    &format!("PV {} Power", idx),
-                    &format!("pv_{}_power", idx),
+                    device_config,
+                    &format!("{name} Power"),
+                    &format!("{key}_power"),
                 ),
                 SensorConfig::voltage(
                     state_topic,
-                    &device_config,
-                    &format!("PV {} Voltage", idx),
-                    &format!("pv_{}_vol", idx),
+                    device_config,
+                    &format!("{name} Voltage"),
+                    &format!("{key}_vol"),
                 ),
                 SensorConfig::current(
                     state_topic,
-                    &device_config,
-                    &format!("PV {} Current", idx),
-                    &format!("pv_{}_cur", idx),
+                    device_config,
+                    &format!("{name} Current"),
+                    &format!("{key}_cur"),
                 ),
                 SensorConfig::energy(
                     state_topic,
-                    &device_config,
-                    &format!("PV {} Daily Yield", idx),
-                    &format!("pv_{}_daily_yield", idx),
+                    device_config,
+                    &format!("{name} Daily Yield"),
+                    &format!("{key}_daily_yield"),
                 ),
                 SensorConfig::energy(
                     state_topic,
-                    &device_config,
-                    &format!("PV {} Energy Total", idx),
-                    &format!("pv_{}_energy_total", idx),
+                    device_config,
+                    &format!("{name} Energy Total"),
+                    &format!("{key}_energy_total"),
                 ),
             ]);
         }
-        for inverter in &self.inverter_state {
-            let idx = inverter.port_id;
+        for (i, inverter) in self.inverters.iter().enumerate() {
+            let idx = self.inverter_id(inverter);
+            if let Some(inverter_metrics) = metrics.inverters.get(i) {
+                sensors.push(SensorConfig::efficiency(
+                    state_topic,
+                    device_config,
+                    &format!("Inverter {} Efficiency", idx),
+                    &format!("inv_{}_efficiency", idx),
+                ));
+                if inverter_metrics.lifetime_energy.is_some() {
+                    sensors.push(SensorConfig::energy(
+                        state_topic,
+                        device_config,
+                        &format!("Inverter {} Lifetime Energy", idx),
+                        &format!("inv_{}_lifetime_energy", idx),
+                    ));
+                }
+            }
+            // a single inverter shows its limit on the number entity of the DTU
+            if self.has_several_inverters() && inverter.power_limit.is_some() {
+                sensors.push(SensorConfig::percentage(
+                    state_topic,
+                    device_config,
+                    &format!("Inverter {} Power Limit", idx),
+                    &format!("inv_{}_power_limit", idx),
+                ));
+            }
             sensors.extend([
                 SensorConfig::power(
                     state_topic,
-                    &device_config,
+                    device_config,
                     &format!("Inverter {} Power", idx),
                     &format!("inv_{}_pv_current_power", idx),
                 ),
                 SensorConfig::temperature(
                     state_topic,
-                    &device_config,
+                    device_config,
                     &format!("Inverter {} Temperature", idx),
                     &format!("inv_{}_temperature", idx),
                 ),
                 SensorConfig::voltage(
                     state_topic,
-                    &device_config,
+                    device_config,
                     &format!("Inverter {} Grid Voltage", idx),
                     &format!("inv_{}_grid_voltage", idx),
                 ),
                 SensorConfig::frequency(
                     state_topic,
-                    &device_config,
+                    device_config,
                     &format!("Inverter {} Grid Frequency", idx),
                     &format!("inv_{}_grid_freq", idx),
                 ),
             ]);
+            // only reported by newer firmware
+            if inverter.reactive_power.is_some() {
+                sensors.push(SensorConfig::reactive_power(
+                    state_topic,
+                    device_config,
+                    &format!("Inverter {} Reactive Power", idx),
+                    &format!("inv_{}_reactive_power", idx),
+                ));
+            }
+            if inverter.power_factor.is_some() {
+                sensors.push(SensorConfig::power_factor(
+                    state_topic,
+                    device_config,
+                    &format!("Inverter {} Power Factor", idx),
+                    &format!("inv_{}_power_factor", idx),
+                ));
+            }
+            for (phase, line) in PHASE_NAMES
+                .iter()
+                .zip(LINE_NAMES)
+                .take(inverter.phases.len())
+            {
+                let label = phase.to_uppercase();
+                sensors.extend([
+                    SensorConfig::voltage(
+                        state_topic,
+                        device_config,
+                        &format!("Inverter {} Phase {} Voltage", idx, label),
+                        &format!("inv_{}_phase_{}_voltage", idx, phase),
+                    ),
+                    SensorConfig::current(
+                        state_topic,
+                        device_config,
+                        &format!("Inverter {} Phase {} Current", idx, label),
+                        &format!("inv_{}_phase_{}_current", idx, phase),
+                    ),
+                    SensorConfig::power(
+                        state_topic,
+                        device_config,
+                        &format!("Inverter {} Phase {} Power", idx, label),
+                        &format!("inv_{}_phase_{}_power", idx, phase),
+                    ),
+                    SensorConfig::voltage(
+                        state_topic,
+                        device_config,
+                        &format!("Inverter {} Line {} Voltage", idx, line.to_uppercase()),
+                        &format!("inv_{}_line_{}_voltage", idx, line),
+                    ),
+                ]);
+            }
+        }
+        for idx in 1..=self.meters.len() {
+            sensors.extend([
+                SensorConfig::power(
+                    state_topic,
+                    device_config,
+                    &format!("Meter {} Power", idx),
+                    &format!("meter_{}_power", idx),
+                ),
+                SensorConfig::energy(
+                    state_topic,
+                    device_config,
+                    &format!("Meter {} Energy Exported", idx),
+                    &format!("meter_{}_energy_exported", idx),
+                ),
+                SensorConfig::energy(
+                    state_topic,
+                    device_config,
+                    &format!("Meter {} Energy Imported", idx),
+                    &format!("meter_{}_energy_imported", idx),
+                ),
+            ]);
         }
         sensors
     }
@@ -9,7 +9,9 @@ pub struct DeviceConfig {
     model: String,
     identifiers: Vec<String>,
     manufacturer: String,
-    sw_version: String, // Software version of the application that supplies the discovered MQTT item.
+    sw_version: String, // Firmware version of the device, if known, or the version of this application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hw_version: Option<String>, // Hardware version of the device.
 }
 
 impl DeviceConfig {
@@ -21,8 +23,15 @@ impl DeviceConfig {
             manufacturer: "Hoymiles".to_string(),
             // Rust compiler sets the CARGO_PKG_VERSION environment from the Cargo.toml .
             sw_version: env!("CARGO_PKG_VERSION").to_string(),
+            hw_version: None,
         }
     }
+
+    pub fn with_versions(mut self, sw_version: String, hw_version: String) -> Self {
+        self.sw_version = sw_version;
+        self.hw_version = Some(hw_version);
+        self
+    }
 }
 
 /// `SensorConfig` is used to define the configuration for a Home Assistant sensor entity
@@ -48,6 +57,8 @@ pub struct SensorConfig {
     device_class: Option<String>, // The type/class of the sensor, e.g. energy, power, temperature, etc.
     #[serde(skip_serializing_if = "Option::is_none")]
     state_class: Option<String>, // The type/class of the state, e.g. measurement, total_increasing, etc.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>, // Set to diagnostic for entities that are not primary readings.
 }
 
 impl SensorConfig {
@@ -71,9 +82,16 @@ impl SensorConfig {
             value_template,
             device: device_config.clone(),
             state_class,
+            entity_category: None,
         }
     }
 
+    /// Marks the sensor as diagnostic entity, e.g. for configuration values.
+    pub fn diagnostic(mut self) -> Self {
+        self.entity_category = Some("diagnostic".to_string());
+        self
+    }
+
     pub fn string(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
         Self::new_sensor(state_topic, device_config, key, name, None, None, None)
     }
@@ -90,6 +108,40 @@ impl SensorConfig {
         )
     }
 
+    pub fn reactive_power(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            Some("reactive_power".to_string()),
+            Some("var".to_string()),
+            Some("measurement".to_string()),
+        )
+    }
+
+    pub fn power_factor(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            Some("power_factor".to_string()),
+            None,
+            Some("measurement".to_string()),
+        )
+    }
+
     pub fn energy(state_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
         Self::new_sensor(
             state_topic,
@@ -160,6 +212,76 @@ impl SensorConfig {
         )
     }
 
+    /// A share of a whole, e.g. a power limit relative to the rated power.
+    pub fn percentage(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            None,
+            Some("%".to_string()),
+            Some("measurement".to_string()),
+        )
+    }
+
+    pub fn specific_yield(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            None,
+            Some("kWh/kWp".to_string()),
+            // computed from the daily yield, a reset at midnight is not a new cycle of a total
+            Some("measurement".to_string()),
+        )
+    }
+
+    pub fn timestamp(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            Some("timestamp".to_string()),
+            None,
+            None,
+        )
+    }
+
+    pub fn signal_strength(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        Self::new_sensor(
+            state_topic,
+            device_config,
+            key,
+            name,
+            Some("signal_strength".to_string()),
+            Some("dBm".to_string()),
+            Some("measurement".to_string()),
+        )
+    }
+
     pub fn frequency(
         state_topic: &str,
         device_config: &DeviceConfig,
@@ -177,3 +299,167 @@ impl SensorConfig {
         )
     }
 }
+
+/// `BinarySensorConfig` is used to define the configuration for a Home Assistant binary sensor,
+/// i.e. a flag whose value is ON or OFF.
+///
+/// More information about the MQTT binary sensor entities can be found here:
+/// https://www.home-assistant.io/integrations/binary_sensor.mqtt/
+///
+#[derive(Serialize)]
+pub struct BinarySensorConfig {
+    pub unique_id: String,  //  A globally unique identifier for the entity.
+    name: String,           // The name of the entity.
+    state_topic: String,    // The MQTT topic where the flag is published.
+    value_template: String, // A template to extract the flag from the mqtt message.
+    device: DeviceConfig, // The device that the entity belongs to, used to group entities together.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>, // The type/class of the flag, e.g. running, connectivity, problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>, // Marks the entity as config or diagnostic.
+}
+
+impl BinarySensorConfig {
+    pub fn new(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+        device_class: Option<&str>,
+    ) -> Self {
+        BinarySensorConfig {
+            unique_id: format!("{}_{}", device_config.identifiers[0], key),
+            name: name.to_string(),
+            state_topic: state_topic.to_string(),
+            value_template: format!("{{{{ value_json.{} }}}}", key),
+            device: device_config.clone(),
+            device_class: device_class.map(str::to_string),
+            entity_category: None,
+        }
+    }
+
+    /// Marks the binary sensor as diagnostic entity.
+    pub fn diagnostic(mut self) -> Self {
+        self.entity_category = Some("diagnostic".to_string());
+        self
+    }
+}
+
+/// `NumberConfig` is used to define the configuration for a Home Assistant number entity,
+/// i.e. a value that can be set from Home Assistant via a command topic.
+///
+/// More information about the MQTT number entities can be found here:
+/// https://www.home-assistant.io/integrations/number.mqtt/
+///
+#[derive(Serialize)]
+pub struct NumberConfig {
+    pub unique_id: String, //  A globally unique identifier for the entity.
+    name: String,          // The name of the entity.
+    command_topic: String, // The MQTT topic to publish new values to.
+    state_topic: String,   // The MQTT topic where the current value is published.
+    device: DeviceConfig, // The device that the entity belongs to, used to group entities together.
+    min: f32,
+    max: f32,
+    step: f32,
+    unit_of_measurement: String,
+}
+
+impl NumberConfig {
+    pub fn percentage(
+        command_topic: &str,
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+        min: f32,
+    ) -> Self {
+        NumberConfig {
+            unique_id: format!("{}_{}", device_config.identifiers[0], key),
+            name: name.to_string(),
+            command_topic: command_topic.to_string(),
+            state_topic: state_topic.to_string(),
+            device: device_config.clone(),
+            min,
+            max: 100.,
+            step: 1.,
+            unit_of_measurement: "%".to_string(),
+        }
+    }
+}
+
+/// `ButtonConfig` is used to define the configuration for a Home Assistant button entity,
+/// i.e. an action that can be triggered from Home Assistant via a command topic.
+///
+/// More information about the MQTT button entities can be found here:
+/// https://www.home-assistant.io/integrations/button.mqtt/
+///
+#[derive(Serialize)]
+pub struct ButtonConfig {
+    pub unique_id: String, //  A globally unique identifier for the entity.
+    name: String,          // The name of the entity.
+    command_topic: String, // The MQTT topic to publish the command to.
+    device: DeviceConfig, // The device that the entity belongs to, used to group entities together.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>, // The type/class of the button, e.g. restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>, // Marks the entity as config or diagnostic.
+}
+
+impl ButtonConfig {
+    pub fn new(command_topic: &str, device_config: &DeviceConfig, name: &str, key: &str) -> Self {
+        ButtonConfig {
+            unique_id: format!("{}_{}", device_config.identifiers[0], key),
+            name: name.to_string(),
+            command_topic: command_topic.to_string(),
+            device: device_config.clone(),
+            device_class: None,
+            entity_category: None,
+        }
+    }
+
+    pub fn restart(
+        command_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+    ) -> Self {
+        ButtonConfig {
+            device_class: Some("restart".to_string()),
+            entity_category: Some("config".to_string()),
+            ..Self::new(command_topic, device_config, name, key)
+        }
+    }
+}
+
+/// `EventConfig` is used to define the configuration for a Home Assistant event entity,
+/// i.e. discrete events like alarms that are not a state.
+///
+/// More information about the MQTT event entities can be found here:
+/// https://www.home-assistant.io/integrations/event.mqtt/
+///
+#[derive(Serialize)]
+pub struct EventConfig {
+    pub unique_id: String,    //  A globally unique identifier for the entity.
+    name: String,             // The name of the entity.
+    state_topic: String,      // The MQTT topic where events are published.
+    device: DeviceConfig, // The device that the entity belongs to, used to group entities together.
+    event_types: Vec<String>, // The values of the event_type attribute that the events carry.
+}
+
+impl EventConfig {
+    pub fn new(
+        state_topic: &str,
+        device_config: &DeviceConfig,
+        name: &str,
+        key: &str,
+        event_types: &[&str],
+    ) -> Self {
+        EventConfig {
+            unique_id: format!("{}_{}", device_config.identifiers[0], key),
+            name: name.to_string(),
+            state_topic: state_topic.to_string(),
+            device: device_config.clone(),
+            event_types: event_types.iter().map(|event| event.to_string()).collect(),
+        }
+    }
+}
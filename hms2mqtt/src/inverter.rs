@@ -1,13 +1,218 @@
+use crate::alarm::Alarm;
+use crate::capture::{Recorder, Replay};
+use crate::command::{InverterCommand, PowerLimit, MIN_POWER_LIMIT};
+use crate::device_info::DeviceInfo;
+use crate::dtu_config::DtuConfig;
+use crate::history::PowerHistory;
+use crate::protocol::{Command, Frame, FrameError, ProtocolError};
+use crate::protos::hoymiles::APPInformationData::{APPInfoDataReqDTO, APPInfoDataResDTO};
+use crate::protos::hoymiles::AppGetHistPower::{AppGetHistPowerReqDTO, AppGetHistPowerResDTO};
+use crate::protos::hoymiles::CommandPB::{CommandReqDTO, CommandResDTO};
+use crate::protos::hoymiles::GetConfig::{GetConfigReqDTO, GetConfigResDTO};
 use crate::protos::hoymiles::RealData::{HMSStateResponse, RealDataResDTO};
-use crc16::{State, MODBUS};
-use log::{debug, error, info, warn};
+use crate::protos::hoymiles::RealDataNew::{RealDataNewReqDTO, RealDataNewResDTO};
+use crate::protos::hoymiles::WarnData::{WInfoReqDTO, WInfoResDTO};
+use crate::reading::{DtuState, Reading};
+use chrono::Local;
+use log::{debug, info, warn};
 use protobuf::Message;
-use std::io::{Read, Write};
+use serde_derive::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 static INVERTER_PORT: &str = "10081";
 
+/// The DTU repeats its previous reading, and restarts its countdown, when it
+/// is asked again within about 30 seconds.
+pub const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(30_500);
+
+// actions of the command request
+const ACTION_DTU_REBOOT: i32 = 1;
+const ACTION_INVERTER_REBOOT: i32 = 3;
+const ACTION_INVERTER_START: i32 = 6;
+const ACTION_INVERTER_SHUTDOWN: i32 = 7;
+const ACTION_LIMIT_POWER: i32 = 8;
+
+fn epoch() -> i32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i32)
+}
+
+/// `InverterError` tells apart the ways a request to the inverter can fail.
+#[derive(Debug)]
+pub enum InverterError {
+    /// The host name could not be resolved to an address.
+    Resolve(std::io::Error),
+    /// No connection could be established, e.g. because the inverter is asleep.
+    Connect(std::io::Error),
+    /// The connection broke down while sending or receiving.
+    Io(std::io::Error),
+    /// The response does not follow the frame layout.
+    Frame(FrameError),
+    /// The payload does not match the checksum declared in the header.
+    Crc { expected: u16, calculated: u16 },
+    /// The payload is not a valid protobuf message.
+    Decode(protobuf::Error),
+    /// Packages of a reading that is split into several ones are missing.
+    Incomplete { received: usize, expected: usize },
+    /// The DTU refused a command with the given error code.
+    Rejected(i32),
+    /// A command could not be sent because its arguments are invalid.
+    InvalidArgument(String),
+}
+
+impl fmt::Display for InverterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InverterError::Resolve(e) => write!(f, "unable to resolve host: {e}"),
+            InverterError::Connect(e) => write!(f, "could not connect: {e}"),
+            InverterError::Io(e) => write!(f, "connection failed: {e}"),
+            InverterError::Frame(e) => write!(f, "invalid response frame: {e}"),
+            InverterError::Crc {
+                expected,
+                calculated,
+            } => write!(
+                f,
+                "CRC mismatch, frame declares {expected:#06x}, payload has {calculated:#06x}"
+            ),
+            InverterError::Decode(e) => write!(f, "could not decode response: {e}"),
+            InverterError::Incomplete { received, expected } => write!(
+                f,
+                "incomplete reading, received {received} of {expected} packages"
+            ),
+            InverterError::Rejected(code) => write!(f, "command rejected with error code {code}"),
+            InverterError::InvalidArgument(e) => write!(f, "invalid argument: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for InverterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InverterError::Resolve(e) | InverterError::Connect(e) | InverterError::Io(e) => Some(e),
+            InverterError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for InverterError {
+    fn from(error: ProtocolError) -> Self {
+        match error {
+            ProtocolError::Io(e) => InverterError::Io(e),
+            ProtocolError::Frame(e) => InverterError::Frame(e),
+            ProtocolError::Crc {
+                expected,
+                calculated,
+            } => InverterError::Crc {
+                expected,
+                calculated,
+            },
+            ProtocolError::Decode(e) => InverterError::Decode(e),
+        }
+    }
+}
+
+// keeps a copy of the bytes read, so that recordings hold what the DTU actually sent
+struct Tee<'a, R> {
+    reader: R,
+    bytes: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for Tee<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.reader.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Reads the response frames to a request.
+///
+/// `frame_count` tells from the first frame how many frames make up the
+/// response. A failure after the first frame stops reading, it is up to
+/// the caller to notice the missing frames.
+fn read_frames(
+    reader: &mut impl Read,
+    frame_count: &dyn Fn(&Frame) -> usize,
+) -> Result<Vec<Frame>, InverterError> {
+    let first = Frame::read(reader)?;
+    let count = frame_count(&first);
+    let mut frames = vec![first];
+    while frames.len() < count {
+        match Frame::read(reader) {
+            Ok(frame) => frames.push(frame),
+            Err(e) => {
+                warn!("received {} of {count} frames: {e}", frames.len());
+                break;
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Merges the packages of a reading that the DTU split up, e.g. a DTU-Pro
+/// with dozens of ports, into a single one.
+///
+/// All packages have to belong to the same reading, the totals of the DTU
+/// are repeated in every package. The reading is incomplete unless there is
+/// a package for every index below the number announced by the first one.
+fn merge_packages(packages: Vec<HMSStateResponse>) -> Result<HMSStateResponse, InverterError> {
+    let mut packages = packages.into_iter();
+    let Some(mut merged) = packages.next() else {
+        return Err(InverterError::Incomplete {
+            received: 0,
+            expected: 1,
+        });
+    };
+    let expected = merged.package_nub.max(1);
+    let in_range = |index: &i32| (0..expected).contains(index);
+    let mut indices = HashSet::from([merged.package_now]);
+    for package in packages {
+        // packages of another reading are no part of this snapshot
+        if package.time != merged.time || package.dtu_sn != merged.dtu_sn {
+            continue;
+        }
+        if !in_range(&package.package_now) {
+            warn!(
+                "ignoring package {} of a reading of {expected} packages",
+                package.package_now
+            );
+            continue;
+        }
+        if indices.insert(package.package_now) {
+            merged.inverter_state.extend(package.inverter_state);
+            merged.port_state.extend(package.port_state);
+        }
+    }
+    // each index from 0 to the announced number of packages has to be there
+    let received = indices.iter().filter(|index| in_range(index)).count();
+    if received < expected as usize {
+        return Err(InverterError::Incomplete {
+            received,
+            expected: expected as usize,
+        });
+    }
+    merged.package_nub = 1;
+    Ok(merged)
+}
+
+/// `RealDataCommand` is the command a DTU is asked for readings with.
+///
+/// Newer HMS/HMT firmware and the DTU-Pro also answer the newer command,
+/// whose reply includes reactive power, power factor, the phases of
+/// three-phase inverters and meter data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RealDataCommand {
+    Legacy,
+    New,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum NetworkState {
     Unknown,
@@ -15,104 +220,548 @@ pub enum NetworkState {
     Offline,
 }
 
-pub struct Inverter<'a> {
-    host: &'a str,
+pub struct Inverter {
+    name: String,
+    host: String,
     state: NetworkState,
     sequence: u16,
+    min_update_interval: Duration,
+    // when the DTU was last asked for a reading, and the latest fresh reading
+    last_update: Option<Instant>,
+    last_reading: Option<DtuState>,
+    // None until the command the firmware supports has been detected
+    real_data_command: Option<RealDataCommand>,
+    rated_power: Option<f32>,
+    power_limit: Option<f32>,
+    // serial numbers of the inverters attached to the DTU, as seen in the latest reading
+    inverter_serials: Vec<i64>,
+    // alarm log as of the previous poll, None until it has been fetched once
+    known_alarms: Option<HashSet<(String, i32, i32, i32)>>,
+    recorder: Option<Recorder>,
+    // answers requests instead of the DTU at host
+    replay: Option<Replay>,
 }
 
-impl<'a> Inverter<'a> {
-    pub fn new(host: &'a str) -> Self {
+impl Inverter {
+    pub fn new(name: &str, host: &str) -> Self {
         Self {
-            host,
+            name: name.to_string(),
+            host: host.to_string(),
             state: NetworkState::Unknown,
             sequence: 0_u16,
+            min_update_interval: MIN_UPDATE_INTERVAL,
+            last_update: None,
+            last_reading: None,
+            real_data_command: None,
+            rated_power: None,
+            power_limit: None,
+            inverter_serials: Vec::new(),
+            known_alarms: None,
+            recorder: None,
+            replay: None,
         }
     }
 
+    /// Sets the rated power in watts, which is needed to apply absolute power limits.
+    pub fn with_rated_power(mut self, rated_power: Option<f32>) -> Self {
+        self.rated_power = rated_power;
+        self
+    }
+
+    /// Overrides the minimum time between two readings, e.g. for a mock DTU without a cache.
+    pub fn with_min_update_interval(mut self, interval: Duration) -> Self {
+        self.min_update_interval = interval;
+        self
+    }
+
+    /// Sets the command to fetch readings with, instead of detecting it on the first reading.
+    pub fn with_real_data_command(mut self, command: Option<RealDataCommand>) -> Self {
+        self.real_data_command = command;
+        self
+    }
+
+    /// Records all requests and responses, e.g. to reproduce a problem later on.
+    pub fn with_recorder(mut self, recorder: Option<Recorder>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Replays a recording instead of talking to the DTU.
+    pub fn with_replay(mut self, replay: Option<Replay>) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Points the inverter to another host, e.g. after the DTU got a new address via DHCP.
+    pub fn set_host(&mut self, host: &str) {
+        info!(
+            "Inverter {:?} moved from {} to {host}",
+            self.name, self.host
+        );
+        self.host = host.to_string();
+        self.state = NetworkState::Unknown;
+    }
+
+    /// The time until the DTU has a fresh reading, zero if it is due.
+    pub fn time_until_update(&self) -> Duration {
+        self.last_update.map_or(Duration::ZERO, |last_update| {
+            self.min_update_interval
+                .saturating_sub(last_update.elapsed())
+        })
+    }
+
+    /// The active power limit in percent as reported by the latest reading.
+    pub fn power_limit(&self) -> Option<f32> {
+        self.power_limit
+    }
+
     fn set_state(&mut self, new_state: NetworkState) {
         if self.state != new_state {
             self.state = new_state;
-            info!("Inverter is {new_state:?}");
+            info!("Inverter at {} is {new_state:?}", self.host);
         }
     }
 
-    pub fn update_state(&mut self) -> Option<HMSStateResponse> {
-        self.sequence = self.sequence.wrapping_add(1);
-
-        let /*mut*/ request = RealDataResDTO::default();
-        // let date = Local::now();
-        // let time_string = date.format("%Y-%m-%d %H:%M:%S").to_string();
-        // request.ymd_hms = time_string;
-        // request.cp = 23 + sequence as i32;
-        // request.offset = 0;
-        // request.time = epoch();
-        let header = b"\x48\x4d\xa3\x03";
-        let request_as_bytes = request.write_to_bytes().expect("serialize to bytes");
-        let crc16 = State::<MODBUS>::calculate(&request_as_bytes);
-        let len = request_as_bytes.len() as u16 + 10u16;
-
-        // compose request message
-        let mut message = Vec::new();
-        message.extend_from_slice(header);
-        message.extend_from_slice(&self.sequence.to_be_bytes());
-        message.extend_from_slice(&crc16.to_be_bytes());
-        message.extend_from_slice(&len.to_be_bytes());
-        message.extend_from_slice(&request_as_bytes);
-
-        let inverter_host = self.host.to_string() + ":" + INVERTER_PORT;
-        let address = match inverter_host.to_socket_addrs() {
-            Ok(mut a) => a.next(),
-            Err(e) => {
-                error!("Unable to resolve domain: {e}");
-                return None;
-            }
+    /// Sends a request frame to the DTU at host and reads its response frames.
+    ///
+    /// All bytes read are kept in `received`, including those of frames that
+    /// turn out to be truncated or garbled.
+    fn exchange(
+        &self,
+        message: &[u8],
+        frame_count: &dyn Fn(&Frame) -> usize,
+        received: &mut Vec<u8>,
+    ) -> Result<Vec<Frame>, InverterError> {
+        // the port is only given for test setups, e.g. a mock DTU
+        let inverter_host = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            self.host.clone() + ":" + INVERTER_PORT
         };
-        if address.is_none() {
-            error!("Unable to parse name");
-            return None;
-        }
+        let address = inverter_host
+            .to_socket_addrs()
+            .map_err(InverterError::Resolve)?
+            .next()
+            .ok_or_else(|| {
+                InverterError::Resolve(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no address found",
+                ))
+            })?;
 
-        let stream = TcpStream::connect_timeout(&address.unwrap(), Duration::from_millis(500));
-        if let Err(e) = stream {
-            debug!("could not connect: {e}");
-            self.set_state(NetworkState::Offline);
-            return None;
-        }
+        let mut stream = TcpStream::connect_timeout(&address, Duration::from_millis(500))
+            .map_err(InverterError::Connect)?;
 
-        let mut stream = stream.unwrap();
         if let Err(e) = stream.set_write_timeout(Some(Duration::new(5, 0))) {
             warn!("could not set write timeout: {e}");
         }
         if let Err(e) = stream.set_read_timeout(Some(Duration::new(5, 0))) {
             warn!("could not set read timeout: {e}");
         }
-        if let Err(e) = stream.write(&message) {
-            debug!(r#"{e}"#);
-            self.set_state(NetworkState::Offline);
-            return None;
+        stream.write_all(message).map_err(InverterError::Io)?;
+
+        read_frames(
+            &mut Tee {
+                reader: stream,
+                bytes: received,
+            },
+            frame_count,
+        )
+    }
+
+    /// Sends a single request to the DTU and waits for the matching response.
+    fn send_request<Req: Message, Res: Message>(
+        &mut self,
+        command: Command,
+        request: &Req,
+    ) -> Result<Res, InverterError> {
+        let mut responses = self.send_request_packages(command, request, |_: &Res| 1)?;
+        Ok(responses.swap_remove(0))
+    }
+
+    /// Sends a single request to the DTU and waits for all packages of its
+    /// response, whose number `package_count` reads from the first one.
+    fn send_request_packages<Req: Message, Res: Message>(
+        &mut self,
+        command: Command,
+        request: &Req,
+        package_count: impl Fn(&Res) -> usize,
+    ) -> Result<Vec<Res>, InverterError> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let message = Frame::request(command, self.sequence, request).encode();
+
+        let frame_count = |frame: &Frame| {
+            frame
+                .decode::<Res>()
+                .map_or(1, |response| package_count(&response))
+        };
+        let mut received = Vec::new();
+        let sequence = self.sequence;
+        let result = match &mut self.replay {
+            Some(replay) => replay
+                .exchange(&message)
+                .map_err(InverterError::Io)
+                .and_then(|bytes| {
+                    received = bytes;
+                    let mut frames = read_frames(&mut received.as_slice(), &frame_count)?;
+                    // the sequence number is not covered by the checksum and follows the current request
+                    frames
+                        .iter_mut()
+                        .for_each(|frame| frame.sequence = sequence);
+                    Ok(frames)
+                }),
+            None => self.exchange(&message, &frame_count, &mut received),
+        };
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&message, &received);
+        }
+        match &result {
+            Ok(_) => self.set_state(NetworkState::Online),
+            Err(InverterError::Connect(_) | InverterError::Io(_)) => {
+                self.set_state(NetworkState::Offline)
+            }
+            // the inverter answered, but the frame is unusable
+            Err(_) => {}
+        }
+        let frames = result?;
+
+        frames
+            .iter()
+            .map(|frame| {
+                frame.check_response(command, self.sequence)?;
+                Ok(frame.decode()?)
+            })
+            .collect()
+    }
+
+    /// Fetches the current reading.
+    ///
+    /// Readings the DTU answered from its cache, and readings asked for
+    /// before the minimum update interval has passed, repeat the previous
+    /// reading and are flagged as stale. In the latter case the DTU is not
+    /// asked at all, as that would only restart its countdown.
+    pub fn update_state(&mut self) -> Result<Reading, InverterError> {
+        if let Some(previous) = &self.last_reading {
+            if self.time_until_update() > Duration::ZERO {
+                return Ok(Reading::new(&self.name, previous.clone()).stale());
+            }
         }
 
-        let mut buf = [0u8; 1024];
-        let read = stream.read(&mut buf);
+        let result = self.fetch_real_data();
+        // the DTU restarts its countdown as soon as it answers, even if packages went missing
+        if matches!(result, Ok(_) | Err(InverterError::Incomplete { .. })) {
+            self.last_update = Some(Instant::now());
+        }
+        let response = result?;
 
-        if let Err(e) = read {
-            debug!("{e}");
-            self.set_state(NetworkState::Offline);
-            return None;
+        // a cached reply carries the timestamp of the reading that was sent before
+        if let Some(previous) = &self.last_reading {
+            if response.time <= previous.time {
+                return Ok(Reading::new(&self.name, response).stale());
+            }
         }
-        let read_length = read.unwrap();
-        let parsed = HMSStateResponse::parse_from_bytes(&buf[10..read_length]);
+        self.power_limit = response.power_limit();
+        self.inverter_serials = response
+            .inverters
+            .iter()
+            .map(|inverter| inverter.serial)
+            .collect();
+        self.last_reading = Some(response.clone());
+        Ok(Reading::new(&self.name, response))
+    }
 
-        if let Err(e) = parsed {
-            debug!("{e}");
-            self.set_state(NetworkState::Offline);
-            return None;
+    /// Fetches a reading with the command the firmware supports.
+    ///
+    /// Unless configured, the newer command is tried first. Firmware that does
+    /// not know it closes the connection, so the legacy command is used from
+    /// then on. An empty answer is served by the legacy command without a
+    /// decision, as are other errors, which may be a single bad reply. The
+    /// detection is then tried again on the next call.
+    fn fetch_real_data(&mut self) -> Result<DtuState, InverterError> {
+        match self.real_data_command {
+            Some(RealDataCommand::Legacy) => self.fetch_real_data_legacy(),
+            Some(RealDataCommand::New) => self.fetch_real_data_new(),
+            None => {
+                match self.fetch_real_data_new() {
+                    Ok(state) if !state.inverters.is_empty() || !state.ports.is_empty() => {
+                        info!("{} supports the new real data command", self.host);
+                        self.real_data_command = Some(RealDataCommand::New);
+                        return Ok(state);
+                    }
+                    // firmware without the command may answer it empty, but so may
+                    // recent firmware while its inverters are asleep
+                    Ok(_) => {
+                        debug!(
+                            "{} answered the new real data command without data",
+                            self.host
+                        );
+                        return self.fetch_real_data_legacy();
+                    }
+                    Err(InverterError::Io(e))
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::UnexpectedEof
+                                | io::ErrorKind::ConnectionReset
+                                | io::ErrorKind::ConnectionAborted
+                        ) =>
+                    {
+                        debug!(
+                            "{} does not support the new real data command: {e}",
+                            self.host
+                        )
+                    }
+                    // an unreachable DTU, a timeout or a garbled reply tell nothing about the firmware
+                    Err(e) => return Err(e),
+                }
+                let state = self.fetch_real_data_legacy()?;
+                info!("{} supports the legacy real data command", self.host);
+                self.real_data_command = Some(RealDataCommand::Legacy);
+                Ok(state)
+            }
         }
-        debug_assert!(parsed.is_ok());
+    }
+
+    fn fetch_real_data_legacy(&mut self) -> Result<DtuState, InverterError> {
+        let request = RealDataResDTO::default();
+        let packages = self.send_request_packages(
+            Command::RealData,
+            &request,
+            |response: &HMSStateResponse| response.package_nub.max(1) as usize,
+        )?;
+        Ok(DtuState::from(&merge_packages(packages)?))
+    }
+
+    fn fetch_real_data_new(&mut self) -> Result<DtuState, InverterError> {
+        let now = Local::now();
+        let request = RealDataNewResDTO {
+            time_ymd_hms: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            offset: now.offset().local_minus_utc(),
+            time: epoch(),
+            ..Default::default()
+        };
+        let response: RealDataNewReqDTO = self.send_request(Command::RealDataNew, &request)?;
+        Ok(DtuState::from(&response))
+    }
+
+    /// Fetches the configuration of the DTU, e.g. its network and server settings.
+    pub fn get_config(&mut self) -> Result<DtuConfig, InverterError> {
+        let request = GetConfigReqDTO {
+            time: epoch(),
+            ..Default::default()
+        };
+        let response: GetConfigResDTO = self.send_request(Command::GetConfig, &request)?;
+        Ok(DtuConfig::from(&response))
+    }
+
+    /// Fetches model and firmware information of the DTU and its inverters.
+    pub fn get_device_info(&mut self) -> Result<DeviceInfo, InverterError> {
+        let request = APPInfoDataReqDTO {
+            time: epoch(),
+            ..Default::default()
+        };
+        let response: APPInfoDataResDTO = self.send_request(Command::AppInfoData, &request)?;
+        Ok(DeviceInfo::from(&response).with_rated_power(self.rated_power))
+    }
+
+    /// Fetches the power curve of the current day, e.g. to fill the gaps of an outage.
+    pub fn get_power_history(&mut self) -> Result<PowerHistory, InverterError> {
+        let request = AppGetHistPowerResDTO {
+            requested_time: epoch(),
+            ..Default::default()
+        };
+        let response: AppGetHistPowerReqDTO =
+            self.send_request(Command::AppGetHistPower, &request)?;
+        Ok(PowerHistory::from(&response))
+    }
+
+    /// Fetches the alarm log of the DTU and returns the alarms that were not
+    /// in the log at the previous call.
+    ///
+    /// The first call only takes note of the alarms already in the log, so
+    /// that a restart of the publisher does not report them again.
+    pub fn get_new_alarms(&mut self) -> Result<Vec<Alarm>, InverterError> {
+        let request = WInfoResDTO {
+            time: epoch(),
+            ..Default::default()
+        };
+        let response: WInfoReqDTO = self.send_request(Command::WarnInfo, &request)?;
+        let alarms: Vec<Alarm> = response.w_info.iter().map(Alarm::from).collect();
+
+        let new_alarms = match &self.known_alarms {
+            Some(known_alarms) => alarms
+                .iter()
+                .filter(|alarm| !known_alarms.contains(&alarm.key()))
+                .cloned()
+                .collect(),
+            None => {
+                info!("{} alarms in the log of {}", alarms.len(), self.host);
+                Vec::new()
+            }
+        };
+        self.known_alarms = Some(alarms.iter().map(Alarm::key).collect());
+        Ok(new_alarms)
+    }
+
+    /// Triggers an action on the DTU and waits for its acknowledgement.
+    fn send_command(
+        &mut self,
+        action: i32,
+        data: String,
+        inverter_serials: Vec<i64>,
+    ) -> Result<(), InverterError> {
+        let time = epoch();
+        let request = CommandResDTO {
+            time,
+            action,
+            // commands addressed to inverters need to name their kind
+            dev_kind: if inverter_serials.is_empty() { 0 } else { 1 },
+            package_nub: 1,
+            tid: time,
+            data,
+            mi_to_sn: inverter_serials,
+            ..Default::default()
+        };
+        let response: CommandReqDTO = self.send_request(Command::Command, &request)?;
+        if response.err_code != 0 {
+            return Err(InverterError::Rejected(response.err_code));
+        }
+        Ok(())
+    }
+
+    /// Sets the active power limit, returns the limit in percent that was applied.
+    pub fn set_power_limit(&mut self, limit: PowerLimit) -> Result<f32, InverterError> {
+        let percent = match limit {
+            PowerLimit::Percent(percent) => percent,
+            PowerLimit::Watts(watts) => match self.rated_power {
+                Some(rated_power) if rated_power > 0. => watts / rated_power * 100.,
+                _ => {
+                    return Err(InverterError::InvalidArgument(
+                        "an absolute power limit requires the rated power to be configured"
+                            .to_string(),
+                    ))
+                }
+            },
+        };
+        if !(MIN_POWER_LIMIT..=100.).contains(&percent) {
+            return Err(InverterError::InvalidArgument(format!(
+                "power limit of {percent:.1}% is out of range"
+            )));
+        }
+
+        // the limit is transmitted in tenths of a percent
+        let data = format!("A:{},B:0,C:0\r", (percent * 10.).round() as i32);
+        self.send_command(ACTION_LIMIT_POWER, data, Vec::new())?;
+        self.power_limit = Some(percent);
+        Ok(percent)
+    }
+
+    /// Sends an action to all inverters attached to the DTU.
+    fn send_inverter_command(&mut self, action: i32) -> Result<(), InverterError> {
+        if self.inverter_serials.is_empty() {
+            return Err(InverterError::InvalidArgument(
+                "the serial number of the inverter is not known before the first reading"
+                    .to_string(),
+            ));
+        }
+        self.send_command(action, String::new(), self.inverter_serials.clone())
+    }
+
+    /// Starts feeding into the grid again after the inverters were turned off.
+    pub fn turn_on(&mut self) -> Result<(), InverterError> {
+        self.send_inverter_command(ACTION_INVERTER_START)
+    }
+
+    /// Stops the inverters from feeding into the grid.
+    pub fn turn_off(&mut self) -> Result<(), InverterError> {
+        self.send_inverter_command(ACTION_INVERTER_SHUTDOWN)
+    }
+
+    /// Restarts the inverters, but not the DTU.
+    pub fn restart(&mut self) -> Result<(), InverterError> {
+        self.send_inverter_command(ACTION_INVERTER_REBOOT)
+    }
+
+    /// Reboots the DTU, e.g. if its Wi-Fi connection hangs.
+    pub fn restart_dtu(&mut self) -> Result<(), InverterError> {
+        self.send_command(ACTION_DTU_REBOOT, String::new(), Vec::new())
+    }
+
+    /// Executes a command and returns it as it was applied.
+    pub fn execute(&mut self, command: &InverterCommand) -> Result<InverterCommand, InverterError> {
+        match command {
+            InverterCommand::SetPowerLimit(limit) => {
+                let percent = self.set_power_limit(*limit)?;
+                return Ok(InverterCommand::SetPowerLimit(PowerLimit::Percent(percent)));
+            }
+            InverterCommand::TurnOn => self.turn_on()?,
+            InverterCommand::TurnOff => self.turn_off()?,
+            InverterCommand::Restart => self.restart()?,
+            InverterCommand::RestartDtu => self.restart_dtu()?,
+        }
+        Ok(command.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::hoymiles::RealData::PortState;
+
+    // package `index` of a reading split into `count` packages, carrying one port each
+    fn package(index: i32, count: i32) -> HMSStateResponse {
+        HMSStateResponse {
+            dtu_sn: "4143A0123456".to_string(),
+            time: 1_718_964_000,
+            package_nub: count,
+            package_now: index,
+            port_state: vec![PortState {
+                pv_port: index + 1,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn packages_are_merged() {
+        let merged = merge_packages(vec![package(1, 3), package(0, 3), package(2, 3)]).unwrap();
+        let ports: Vec<_> = merged.port_state.iter().map(|port| port.pv_port).collect();
+        assert_eq!(ports, [2, 1, 3]);
+        assert_eq!(merged.package_nub, 1);
+    }
+
+    #[test]
+    fn missing_package_is_reported() {
+        let result = merge_packages(vec![package(0, 3), package(2, 3), package(2, 3)]);
+        assert!(matches!(
+            result,
+            Err(InverterError::Incomplete {
+                received: 2,
+                expected: 3
+            })
+        ));
+    }
 
-        let response = parsed.unwrap();
-        self.set_state(NetworkState::Online);
-        Some(response)
+    #[test]
+    fn package_out_of_range_is_ignored() {
+        // as many packages as announced, but one of them does not belong to the reading
+        let result = merge_packages(vec![package(0, 2), package(2, 2)]);
+        assert!(matches!(
+            result,
+            Err(InverterError::Incomplete {
+                received: 1,
+                expected: 2
+            })
+        ));
+        let merged = merge_packages(vec![package(0, 2), package(5, 2), package(1, 2)]).unwrap();
+        assert_eq!(merged.port_state.len(), 2);
     }
 }
@@ -1,11 +1,28 @@
 // externally visible interfaces
+pub mod alarm;
+pub mod analysis;
+pub mod capture;
+pub mod command;
+pub mod derived;
+pub mod device_info;
+pub mod discovery;
+pub mod dtu_config;
+pub mod history;
 pub mod home_assistant;
 pub mod inverter;
+pub mod lifetime;
 pub mod metric_collector;
+pub mod mock_dtu;
 pub mod mqtt_config;
 pub mod mqtt_wrapper;
+pub mod plausibility;
+pub mod protocol;
+pub mod reading;
 pub mod simple_mqtt;
+pub mod sun;
 
 // internal interfaces
+#[cfg(test)]
+mod fixtures;
 mod home_assistant_config;
 mod protos;
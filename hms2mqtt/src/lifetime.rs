@@ -0,0 +1,251 @@
+use crate::reading::{serial_to_string, PortState, Reading};
+use chrono::{Local, NaiveDate};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// `EnergyCounter` follows the energy of a single port across daily resets
+/// and glitches of the DTU.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnergyCounter {
+    /// Energy since the port was first seen, including the total it reported then, [Wh]
+    pub energy: f64,
+    /// Daily yield the energy was last updated with, [Wh]
+    pub daily_yield: f64,
+    /// Local date the daily yield was last reset on, or the counter was created on
+    pub date: NaiveDate,
+    /// Total energy the port reported along with the daily yield, missing in older state files, [Wh]
+    #[serde(default)]
+    pub energy_total: Option<f64>,
+}
+
+impl EnergyCounter {
+    fn new(port: &PortState, date: NaiveDate) -> Self {
+        Self {
+            energy: port.energy_total,
+            daily_yield: port.daily_yield,
+            date,
+            energy_total: Some(port.energy_total),
+        }
+    }
+
+    // a yield on a new date is a new day's if the total moved by about the yield rather than by the increase
+    fn is_new_day(&self, energy_total: f64, daily_yield: f64, date: NaiveDate) -> bool {
+        date > self.date
+            && self.energy_total.is_some_and(|previous| {
+                energy_total - previous >= daily_yield - self.daily_yield / 2.
+            })
+    }
+
+    // returns whether the counter changed
+    fn update(&mut self, energy_total: f64, daily_yield: f64, date: NaiveDate) -> bool {
+        if self.is_new_day(energy_total, daily_yield, date) {
+            // the reset happened while the port was not seen, e.g. overnight
+            self.energy += daily_yield;
+            self.date = date;
+        } else if daily_yield > self.daily_yield {
+            // after midnight, this may still be a late update of yesterday's yield
+            self.energy += daily_yield - self.daily_yield;
+        } else if date > self.date && daily_yield < self.daily_yield {
+            // the DTU started counting the new day from zero
+            self.energy += daily_yield;
+            self.date = date;
+        } else {
+            // a glitch, or yesterday's yield repeated after midnight, is left out
+            return false;
+        }
+        self.daily_yield = daily_yield;
+        self.energy_total = Some(energy_total);
+        true
+    }
+}
+
+/// `LifetimeEnergy` keeps a lifetime energy counter per port that never
+/// decreases, persisted in a state file to survive restarts.
+///
+/// The counters follow the daily yield rather than the total energy of the
+/// ports, which some firmware briefly reports too low after a restart of the DTU.
+#[derive(Debug, Default)]
+pub struct LifetimeEnergy {
+    path: Option<PathBuf>,
+    counters: BTreeMap<String, EnergyCounter>,
+    // the counters changed since they were last saved
+    changed: bool,
+}
+
+fn port_key(dtu_sn: &str, port: &PortState) -> String {
+    format!(
+        "{dtu_sn}/{}/{}",
+        serial_to_string(port.inverter_serial),
+        port.port
+    )
+}
+
+impl LifetimeEnergy {
+    /// Reads the counters from the state file, which is created on the first save.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let counters = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            counters,
+            changed: false,
+        })
+    }
+
+    /// Writes the counters to the state file, if there is one and they changed since the last save.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.changed) else {
+            return Ok(());
+        };
+        // a crash while writing must not destroy the previous state
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(&self.counters)?)?;
+        fs::rename(temporary, path)?;
+        self.changed = false;
+        Ok(())
+    }
+
+    /// Updates the counters with the reading and adds them to its metrics.
+    ///
+    /// The counter of an inverter is the sum of the counters of its ports.
+    pub fn update(&mut self, reading: &mut Reading) {
+        if reading.stale {
+            return;
+        }
+        let state = &reading.state;
+        let date = state.time.with_timezone(&Local).date_naive();
+        for (i, port) in state.ports.iter().enumerate() {
+            let counter = self
+                .counters
+                .entry(port_key(&state.dtu_sn, port))
+                .and_modify(|counter| {
+                    self.changed |= counter.update(port.energy_total, port.daily_yield, date)
+                })
+                .or_insert_with(|| {
+                    self.changed = true;
+                    EnergyCounter::new(port, date)
+                });
+            if let Some(metrics) = reading.metrics.ports.get_mut(i) {
+                metrics.lifetime_energy = Some(counter.energy);
+            }
+        }
+        for (inverter, metrics) in state.inverters.iter().zip(&mut reading.metrics.inverters) {
+            metrics.lifetime_energy = Some(
+                state
+                    .ports
+                    .iter()
+                    .filter(|port| port.inverter_serial == inverter.serial)
+                    .map(|port| self.counters[&port_key(&state.dtu_sn, port)].energy)
+                    .sum(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{noon, StateBuilder};
+    use chrono::{DateTime, Duration, Utc};
+
+    fn reading(time: DateTime<Utc>, energy_total: f64, daily_yield: f64) -> Reading {
+        StateBuilder::new(time)
+            .port(0.)
+            .energy(energy_total, daily_yield)
+            .reading()
+    }
+
+    fn energy(lifetime_energy: &mut LifetimeEnergy, mut reading: Reading) -> f64 {
+        lifetime_energy.update(&mut reading);
+        let port = reading.metrics.ports[0].lifetime_energy.unwrap();
+        assert_eq!(reading.metrics.inverters[0].lifetime_energy, Some(port));
+        port
+    }
+
+    #[test]
+    fn counter_never_decreases() {
+        let today = noon().date_naive();
+        let tomorrow = today.succ_opt().unwrap();
+        let mut counter = EnergyCounter {
+            energy: 10_000.,
+            daily_yield: 500.,
+            date: today,
+            energy_total: Some(10_000.),
+        };
+
+        // a DTU restart briefly reports a lower yield, which is not followed
+        assert!(counter.update(10_200., 700., today));
+        assert!(!counter.update(10_200., 600., today));
+        assert!(counter.update(10_300., 800., today));
+        assert_eq!(counter.energy, 10_300.);
+
+        // yesterday's yield after midnight is not counted twice, a late update only adds what is new
+        assert!(!counter.update(10_300., 800., tomorrow));
+        assert!(counter.update(10_350., 850., tomorrow));
+        assert_eq!(counter.energy, 10_350.);
+
+        // the reset starts a new day, after which a glitch is not taken for another reset
+        assert!(counter.update(10_350., 0., tomorrow));
+        assert!(counter.update(10_650., 300., tomorrow));
+        assert!(!counter.update(10_650., 100., tomorrow));
+        assert_eq!((counter.energy, counter.date), (10_650., tomorrow));
+    }
+
+    #[test]
+    fn restart_on_the_next_day() {
+        let today = noon().date_naive();
+        let tomorrow = today.succ_opt().unwrap();
+        let mut counter = EnergyCounter {
+            energy: 10_300.,
+            daily_yield: 800.,
+            date: today,
+            energy_total: Some(10_300.),
+        };
+
+        // the first reading of the next day already has a higher yield than yesterday's
+        assert!(counter.update(11_800., 1_500., tomorrow));
+        assert_eq!((counter.energy, counter.date), (11_800., tomorrow));
+        assert!(counter.update(11_900., 1_600., tomorrow));
+        assert_eq!(counter.energy, 11_900.);
+    }
+
+    #[test]
+    fn counters_survive_restarts() {
+        let path = std::env::temp_dir().join(format!("hms-state-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut lifetime_energy = LifetimeEnergy::load(&path).unwrap();
+        // nothing is written until a counter changed
+        lifetime_energy.save().unwrap();
+        assert!(!path.exists());
+
+        // a new port starts at the total energy it reports
+        let hour = Duration::hours(1);
+        assert_eq!(
+            energy(&mut lifetime_energy, reading(noon(), 10_000., 500.)),
+            10_000.
+        );
+        assert_eq!(
+            energy(&mut lifetime_energy, reading(noon() + hour, 2_000., 700.)),
+            10_200.
+        );
+        lifetime_energy.save().unwrap();
+
+        let mut lifetime_energy = LifetimeEnergy::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            energy(
+                &mut lifetime_energy,
+                reading(noon() + hour * 2, 10_300., 800.)
+            ),
+            10_300.
+        );
+    }
+}
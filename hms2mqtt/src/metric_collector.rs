@@ -1,5 +1,38 @@
-use crate::protos::hoymiles::RealData::HMSStateResponse;
+use crate::alarm::Alarm;
+use crate::analysis::Underperformance;
+use crate::command::{CommandResponse, InverterCommand};
+use crate::device_info::DeviceInfo;
+use crate::dtu_config::DtuConfig;
+use crate::history::PowerHistory;
+use crate::reading::Reading;
 
 pub trait MetricCollector {
-    fn publish(&mut self, hms_state: &HMSStateResponse);
+    fn publish(&mut self, reading: &Reading);
+
+    // The DTU configuration changes rarely and is fetched on a much lower
+    // frequency than readings. Collectors that don't care can ignore it.
+    fn publish_config(&mut self, _inverter: &str, _config: &DtuConfig) {}
+
+    // Model and firmware versions are fetched on the same schedule as the configuration.
+    fn publish_device_info(&mut self, _inverter: &str, _info: &DeviceInfo) {}
+
+    // Alarms are discrete events that are published once, when they first
+    // show up in the alarm log of the DTU.
+    fn publish_alarm(&mut self, _inverter: &str, _alarm: &Alarm) {}
+
+    // Like alarms, an underperforming port is published once, when it is found.
+    fn publish_underperformance(&mut self, _inverter: &str, _event: &Underperformance) {}
+
+    // The power curve of the current day is fetched after the inverter was
+    // unreachable. It is meant for collectors that store time series and can
+    // fill gaps; collectors that only mirror the current state ignore it.
+    fn publish_history(&mut self, _inverter: &str, _history: &PowerHistory) {}
+
+    // Commands that reached the collector, e.g. via MQTT, together with the
+    // name of the inverter they are addressed to.
+    fn poll_commands(&mut self) -> Vec<(String, InverterCommand)> {
+        Vec::new()
+    }
+
+    fn publish_command_response(&mut self, _response: &CommandResponse) {}
 }
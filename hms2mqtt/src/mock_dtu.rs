@@ -0,0 +1,566 @@
+use crate::protocol::{Command, Frame, ProtocolError};
+use crate::protos::hoymiles::APPInformationData::{APPInfoDataResDTO, APPPvInfoMO};
+use crate::protos::hoymiles::AppGetHistPower::AppGetHistPowerReqDTO;
+use crate::protos::hoymiles::CommandPB::{CommandReqDTO, CommandResDTO};
+use crate::protos::hoymiles::GetConfig::GetConfigResDTO;
+use crate::protos::hoymiles::RealData::{HMSStateResponse, InverterState, PortState};
+use crate::protos::hoymiles::RealDataNew::{PvMO, RealDataNewReqDTO, SGSMO, TGSMO};
+use crate::protos::hoymiles::WarnData::{WInfoMO, WInfoReqDTO};
+use log::{debug, info, warn};
+use serde_derive::Deserialize;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the firmware hands out the same reading until it polled the inverter again
+static CACHE_DURATION_DEFAULT: u64 = 30;
+
+// [s] between two samples of the power curve
+const HISTORY_STEP_TIME: i32 = 300;
+
+fn epoch() -> i32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .try_into()
+        .unwrap_or_default()
+}
+
+/// `MockPort` is a single PV input of the simulated inverter.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockPort {
+    /// [V]
+    pub voltage: f32,
+    /// [A]
+    pub current: f32,
+    /// [Wh]
+    #[serde(default)]
+    pub energy_total: i32,
+    /// [Wh]
+    #[serde(default)]
+    pub daily_yield: i32,
+}
+
+/// `MockState` describes a reading of the simulated inverter in plain units.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockState {
+    /// [V]
+    pub grid_voltage: f32,
+    /// [Hz]
+    pub grid_freq: f32,
+    /// [°C]
+    pub temperature: f32,
+    pub ports: Vec<MockPort>,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        let port = MockPort {
+            voltage: 35.,
+            current: 8.,
+            energy_total: 1_000_000,
+            daily_yield: 1_200,
+        };
+        Self {
+            grid_voltage: 230.,
+            grid_freq: 50.,
+            temperature: 35.,
+            ports: vec![port.clone(), port],
+        }
+    }
+}
+
+/// `MockAlarm` is an entry of the alarm log of the simulated inverter.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockAlarm {
+    pub code: i32,
+    pub number: i32,
+    pub start_time: i32,
+    /// 0 while the alarm is active
+    #[serde(default)]
+    pub end_time: i32,
+}
+
+/// `Step` is what the mock DTU does on a request for a fresh reading.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Step {
+    /// Answers with the given reading.
+    Reading(MockState),
+    /// Closes the connection without an answer, like a DTU that is busy talking to the cloud.
+    DropConnection,
+    /// Sends only the first half of the answer and closes the connection.
+    TruncateFrame,
+    /// Sends the answer with a checksum that does not match its payload.
+    CorruptFrame,
+    /// Sends all packages of a reading but the last one and closes the connection.
+    MissingPackage,
+    /// Answers without inverters and ports, like some firmware does while its inverters are asleep.
+    EmptyReading,
+}
+
+/// `MockConfig` is the script of a mock DTU, e.g. as read from a file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockConfig {
+    pub dtu_sn: String,
+    pub inverter_serial: i64,
+    /// [s] during which a reading is repeated
+    #[serde(default = "default_cache_duration")]
+    pub cache_duration: u64,
+    /// Answers the newer real data command, like recent firmware does.
+    #[serde(default)]
+    pub real_data_new: bool,
+    /// Splits readings of the legacy command into packages of this many ports, like a DTU-Pro.
+    #[serde(default)]
+    pub ports_per_package: Option<usize>,
+    /// Simulates a three-phase inverter with balanced phases, which only the newer command tells.
+    #[serde(default)]
+    pub three_phase: bool,
+    /// Steps are played in order and start over once the end is reached.
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Alarm logs handed out in order, the last one is repeated.
+    #[serde(default)]
+    pub alarm_logs: Vec<Vec<MockAlarm>>,
+}
+
+fn default_cache_duration() -> u64 {
+    CACHE_DURATION_DEFAULT
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            dtu_sn: "4143A0000000".to_string(),
+            inverter_serial: 0x114400000000,
+            cache_duration: CACHE_DURATION_DEFAULT,
+            real_data_new: false,
+            ports_per_package: None,
+            three_phase: false,
+            steps: Vec::new(),
+            alarm_logs: Vec::new(),
+        }
+    }
+}
+
+/// `MockDtu` speaks the protocol of a DTU on port 10081, so that `Inverter`
+/// can be exercised without hardware.
+///
+/// Like the real firmware, it repeats its previous reading within the cache
+/// duration and can be scripted to drop connections or truncate frames.
+pub struct MockDtu {
+    listener: TcpListener,
+    config: MockConfig,
+    position: usize,
+    alarm_position: usize,
+    power_limit: f32,
+    last_state: MockState,
+    last_time: i32,
+    // when the latest reading was taken, it is repeated until the cache expires
+    cached: Option<Instant>,
+}
+
+// the reading itself is the latest state, which is encoded for the command that asked for it
+enum Answer {
+    Reading,
+    Truncated,
+    Corrupt,
+    MissingPackage,
+    Empty,
+    Drop,
+}
+
+impl MockDtu {
+    pub fn bind(address: impl ToSocketAddrs, config: MockConfig) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+            config,
+            position: 0,
+            alarm_position: 0,
+            power_limit: 100.,
+            last_state: MockState::default(),
+            last_time: 0,
+            cached: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answers requests until the listener fails, one connection at a time like the DTU.
+    pub fn serve(mut self) -> io::Result<()> {
+        info!("mock DTU listening on {}", self.local_addr()?);
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            debug!("connection from {peer}");
+            if let Err(e) = self.handle_connection(stream) {
+                debug!("connection from {peer} closed: {e}");
+            }
+        }
+    }
+
+    fn handle_connection(&mut self, mut stream: TcpStream) -> Result<(), ProtocolError> {
+        loop {
+            let request = Frame::read(&mut stream)?;
+            let Some(command) = Command::from_id(request.command) else {
+                warn!("unknown command {:02x?}", request.command);
+                return Ok(());
+            };
+            let sequence = request.sequence;
+            let responses = match command {
+                Command::RealData | Command::RealDataNew
+                    if command == Command::RealData || self.config.real_data_new =>
+                {
+                    let answer = self.answer_reading();
+                    let mut frames = self.reading_frames(command, sequence);
+                    match answer {
+                        Answer::Reading => frames,
+                        Answer::Truncated => {
+                            let bytes = frames[0].encode();
+                            stream
+                                .write_all(&bytes[..bytes.len() / 2])
+                                .map_err(ProtocolError::Io)?;
+                            return Ok(());
+                        }
+                        Answer::Corrupt => {
+                            let mut bytes = frames[0].encode();
+                            // the checksum is stored in bytes 6 and 7 of the header
+                            bytes[6] ^= 0xff;
+                            stream.write_all(&bytes).map_err(ProtocolError::Io)?;
+                            return Ok(());
+                        }
+                        Answer::MissingPackage => {
+                            frames.pop();
+                            for frame in frames {
+                                stream
+                                    .write_all(&frame.encode())
+                                    .map_err(ProtocolError::Io)?;
+                            }
+                            return Ok(());
+                        }
+                        Answer::Empty if command == Command::RealDataNew => {
+                            vec![Frame::response(
+                                command,
+                                sequence,
+                                &RealDataNewReqDTO {
+                                    device_serial_number: self.config.dtu_sn.clone(),
+                                    timestamp: epoch(),
+                                    ..Default::default()
+                                },
+                            )]
+                        }
+                        Answer::Empty => vec![Frame::response(
+                            command,
+                            sequence,
+                            &HMSStateResponse {
+                                dtu_sn: self.config.dtu_sn.clone(),
+                                time: epoch(),
+                                ..Default::default()
+                            },
+                        )],
+                        Answer::Drop => return Ok(()),
+                    }
+                }
+                Command::GetConfig => {
+                    vec![Frame::response(command, sequence, &self.config_response())]
+                }
+                Command::AppInfoData => {
+                    vec![Frame::response(command, sequence, &self.info_response())]
+                }
+                Command::WarnInfo => {
+                    vec![Frame::response(command, sequence, &self.alarm_response())]
+                }
+                Command::AppGetHistPower => {
+                    vec![Frame::response(command, sequence, &self.history_response())]
+                }
+                Command::Command => {
+                    let response = self.execute(&request.decode()?);
+                    vec![Frame::response(command, sequence, &response)]
+                }
+                command => {
+                    warn!("unsupported command {command:?}");
+                    return Ok(());
+                }
+            };
+            for response in responses {
+                stream
+                    .write_all(&response.encode())
+                    .map_err(ProtocolError::Io)?;
+            }
+        }
+    }
+
+    fn alarm_response(&mut self) -> WInfoReqDTO {
+        let w_info = self
+            .config
+            .alarm_logs
+            .get(self.alarm_position)
+            .or(self.config.alarm_logs.last())
+            .into_iter()
+            .flatten()
+            .map(|alarm| WInfoMO {
+                pv_sn: self.config.inverter_serial,
+                w_code: alarm.code,
+                w_num: alarm.number,
+                w_time1: alarm.start_time,
+                w_time2: alarm.end_time,
+                ..Default::default()
+            })
+            .collect();
+        self.alarm_position += 1;
+        WInfoReqDTO {
+            dtu_sn: self.config.dtu_sn.clone(),
+            time: epoch(),
+            w_info,
+            ..Default::default()
+        }
+    }
+
+    // the curve of the current day at the power of the last reading, as if it had been constant
+    fn history_response(&self) -> AppGetHistPowerReqDTO {
+        let port_state = self.port_state();
+        let now = epoch();
+        // the day starts at midnight UTC for simplicity
+        let start_time = now - now % 86_400;
+        let samples = (now - start_time) / HISTORY_STEP_TIME + 1;
+        AppGetHistPowerReqDTO {
+            serial_number: self.config.inverter_serial,
+            power_array: vec![Self::ac_power(&port_state); samples as usize],
+            daily_energy: port_state.iter().map(|port| port.pv_daily_yield).sum(),
+            start_time,
+            step_time: HISTORY_STEP_TIME,
+            ..Default::default()
+        }
+    }
+
+    fn next_step(&mut self) -> Step {
+        let steps = &self.config.steps;
+        if steps.is_empty() {
+            return Step::Reading(MockState::default());
+        }
+        let step = steps[self.position].clone();
+        self.position = (self.position + 1) % steps.len();
+        step
+    }
+
+    fn answer_reading(&mut self) -> Answer {
+        let cache_duration = Duration::from_secs(self.config.cache_duration);
+        if let Some(created) = self.cached {
+            if created.elapsed() < cache_duration {
+                // an early request restarts the countdown
+                self.cached = Some(Instant::now());
+                return Answer::Reading;
+            }
+        }
+        match self.next_step() {
+            Step::Reading(state) => {
+                self.last_state = state;
+                // the timestamp tells fresh readings apart, so it has to advance even within a second
+                self.last_time = epoch().max(self.last_time + 1);
+                self.cached = Some(Instant::now());
+                Answer::Reading
+            }
+            Step::DropConnection => Answer::Drop,
+            // a truncated frame never reaches the app, so it is not cached
+            Step::TruncateFrame => Answer::Truncated,
+            Step::CorruptFrame => Answer::Corrupt,
+            Step::MissingPackage => Answer::MissingPackage,
+            Step::EmptyReading => Answer::Empty,
+        }
+    }
+
+    fn reading_frames(&self, command: Command, sequence: u16) -> Vec<Frame> {
+        if command == Command::RealDataNew {
+            return vec![Frame::response(
+                command,
+                sequence,
+                &self.new_state_response(),
+            )];
+        }
+        let mut state = self.state_response();
+        let Some(size) = self.config.ports_per_package.filter(|size| *size > 0) else {
+            return vec![Frame::response(command, sequence, &state)];
+        };
+        // the first package carries the inverters, every package repeats the totals and carries some ports
+        let port_state = std::mem::take(&mut state.port_state);
+        let chunks: Vec<_> = port_state.chunks(size).collect();
+        let package_nub = chunks.len().max(1) as i32;
+        (0..package_nub)
+            .map(|i| {
+                let mut package = state.clone();
+                if i > 0 {
+                    package.inverter_state.clear();
+                }
+                package.package_nub = package_nub;
+                package.package_now = i;
+                package.port_state = chunks.get(i as usize).map_or(Vec::new(), |c| c.to_vec());
+                Frame::response(command, sequence, &package)
+            })
+            .collect()
+    }
+
+    fn port_state(&self) -> Vec<PortState> {
+        let serial = self.config.inverter_serial;
+        self.last_state
+            .ports
+            .iter()
+            .enumerate()
+            .map(|(i, port)| PortState {
+                pv_sn: serial,
+                pv_port: i as i32 + 1,
+                pv_vol: (port.voltage * 10.).round() as i32,
+                pv_cur: (port.current * 100.).round() as i32,
+                pv_power: (port.voltage * port.current * 10.).round() as i32,
+                pv_energy_total: port.energy_total,
+                pv_daily_yield: port.daily_yield,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // [W], factor 0.1, of a typical conversion efficiency of 96%
+    fn ac_power(port_state: &[PortState]) -> i32 {
+        let dc_power: i32 = port_state.iter().map(|port| port.pv_power).sum();
+        (dc_power as f32 * 0.96).round() as i32
+    }
+
+    fn state_response(&self) -> HMSStateResponse {
+        let state = &self.last_state;
+        let port_state = self.port_state();
+        let ac_power = Self::ac_power(&port_state);
+        HMSStateResponse {
+            dtu_sn: self.config.dtu_sn.clone(),
+            time: self.last_time,
+            device_nub: 1,
+            pv_nub: port_state.len() as i32,
+            package_nub: 1,
+            inverter_state: vec![InverterState {
+                inv_id: self.config.inverter_serial,
+                port_id: 1,
+                grid_voltage: (state.grid_voltage * 10.).round() as i32,
+                grid_freq: (state.grid_freq * 100.).round() as i32,
+                pv_current_power: ac_power,
+                power_limit: (self.power_limit * 10.).round() as i32,
+                temperature: (state.temperature * 10.).round() as i32,
+                ..Default::default()
+            }],
+            pv_current_power: ac_power,
+            pv_daily_yield: port_state.iter().map(|port| port.pv_daily_yield).sum(),
+            port_state,
+            ..Default::default()
+        }
+    }
+
+    fn new_state_response(&self) -> RealDataNewReqDTO {
+        let state = &self.last_state;
+        let port_state = self.port_state();
+        let ac_power = Self::ac_power(&port_state);
+        let grid_voltage = (state.grid_voltage * 10.).round() as i32;
+        let frequency = (state.grid_freq * 100.).round() as i32;
+        let temperature = (state.temperature * 10.).round() as i32;
+        let (sgs_data, tgs_data) = if self.config.three_phase {
+            // the power is split evenly, line voltages are larger by the square root of 3
+            let current = (ac_power as f32 * 10. / 3. / state.grid_voltage.max(1.)).round() as i32;
+            let line_voltage = (state.grid_voltage * 3_f32.sqrt() * 10.).round() as i32;
+            let inverter = TGSMO {
+                serial_number: self.config.inverter_serial,
+                voltage_phase_A: grid_voltage,
+                voltage_phase_B: grid_voltage,
+                voltage_phase_C: grid_voltage,
+                voltage_line_AB: line_voltage,
+                voltage_line_BC: line_voltage,
+                voltage_line_CA: line_voltage,
+                frequency,
+                active_power: ac_power,
+                current_phase_A: current,
+                current_phase_B: current,
+                current_phase_C: current,
+                power_factor: 1000,
+                temperature,
+                link_status: 1,
+                ..Default::default()
+            };
+            (Vec::new(), vec![inverter])
+        } else {
+            let inverter = SGSMO {
+                serial_number: self.config.inverter_serial,
+                voltage: grid_voltage,
+                frequency,
+                active_power: ac_power,
+                // the current follows from the power, at a power factor of 1
+                current: (ac_power as f32 * 10. / state.grid_voltage.max(1.)).round() as i32,
+                power_factor: 1000,
+                temperature,
+                link_status: 1,
+                power_limit: (self.power_limit * 10.).round() as i32,
+                ..Default::default()
+            };
+            (vec![inverter], Vec::new())
+        };
+        RealDataNewReqDTO {
+            device_serial_number: self.config.dtu_sn.clone(),
+            timestamp: self.last_time,
+            sgs_data,
+            tgs_data,
+            pv_data: port_state
+                .iter()
+                .map(|port| PvMO {
+                    serial_number: port.pv_sn,
+                    port_number: port.pv_port,
+                    voltage: port.pv_vol,
+                    current: port.pv_cur,
+                    power: port.pv_power,
+                    energy_total: port.pv_energy_total,
+                    energy_daily: port.pv_daily_yield,
+                    ..Default::default()
+                })
+                .collect(),
+            dtu_power: ac_power,
+            dtu_daily_energy: port_state.iter().map(|port| port.pv_daily_yield).sum(),
+            ..Default::default()
+        }
+    }
+
+    fn config_response(&self) -> GetConfigResDTO {
+        GetConfigResDTO {
+            time: epoch(),
+            dtu_sn: self.config.dtu_sn.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn info_response(&self) -> APPInfoDataResDTO {
+        APPInfoDataResDTO {
+            dtu_sn: self.config.dtu_sn.clone(),
+            time: epoch(),
+            device_nub: 1,
+            pv_nub: 1,
+            pv_info: vec![APPPvInfoMO {
+                pv_sn: self.config.inverter_serial,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn execute(&mut self, command: &CommandResDTO) -> CommandReqDTO {
+        // power limits are sent as "A:<tenths of a percent>,B:0,C:0\r"
+        if let Some(limit) = command
+            .data
+            .strip_prefix("A:")
+            .and_then(|data| data.split(',').next())
+            .and_then(|limit| limit.parse::<f32>().ok())
+        {
+            self.power_limit = limit / 10.;
+        }
+        CommandReqDTO {
+            dtu_sn: self.config.dtu_sn.clone(),
+            time: epoch(),
+            action: command.action,
+            tid: command.tid,
+            ..Default::default()
+        }
+    }
+}
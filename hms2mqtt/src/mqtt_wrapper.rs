@@ -22,5 +22,9 @@ pub trait MqttWrapper {
         S: Clone + Into<String>,
         V: Clone + Into<Vec<u8>>;
 
+    // Returns the next message received on one of the subscribed topics
+    // without blocking, i.e. None if there is no message pending.
+    fn try_receive(&mut self) -> Option<(String, Vec<u8>)>;
+
     fn new(config: &MqttConfig, suffix: &str) -> Self;
 }
@@ -0,0 +1,374 @@
+use crate::reading::{serial_to_string, DtuState, Reading};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// `Field` is a value of a reading that rules can be configured for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// AC power of the DTU, [W]
+    Power,
+    /// [V]
+    GridVoltage,
+    /// [Hz]
+    GridFrequency,
+    /// [°C]
+    Temperature,
+    /// AC power of an inverter, [W]
+    InverterPower,
+    /// [V]
+    PortVoltage,
+    /// [A]
+    PortCurrent,
+    /// [W]
+    PortPower,
+}
+
+/// `Policy` tells what happens to a reading that fails a rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// The reading is not published at all.
+    Drop,
+    /// The value is replaced by the nearest plausible one.
+    Clamp,
+    /// The reading is published as it is, along with the failed rules.
+    #[default]
+    Flag,
+}
+
+/// `Rule` bounds the values of a field.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    pub field: Field,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    /// Largest change per second between two readings
+    pub max_rate: Option<f32>,
+    /// Overrides the policy of the configuration for this rule
+    pub policy: Option<Policy>,
+}
+
+/// `PlausibilityConfig` holds the rules that readings are checked against.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PlausibilityConfig {
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Largest ratio of the AC power of an inverter to the DC power of its ports
+    pub max_ac_dc_ratio: Option<f32>,
+    /// Overrides the policy of the configuration for the AC/DC cross-check
+    pub ac_dc_policy: Option<Policy>,
+}
+
+/// `Check` is the kind of check a value failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Range,
+    Rate,
+    CrossCheck,
+}
+
+/// `Violation` is a value of a reading that failed a check.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Violation {
+    pub field: Field,
+    /// Serial number of the inverter, followed by the port number for port fields, empty for the DTU
+    pub source: String,
+    pub check: Check,
+    pub value: f32,
+    pub policy: Policy,
+}
+
+// the values of a field, by the inverter or port they belong to
+fn values_mut(state: &mut DtuState, field: Field) -> Vec<(String, &mut f32)> {
+    match field {
+        Field::Power => vec![(String::new(), &mut state.power)],
+        Field::GridVoltage | Field::GridFrequency | Field::Temperature | Field::InverterPower => {
+            state
+                .inverters
+                .iter_mut()
+                .map(|inverter| {
+                    let value = match field {
+                        Field::GridVoltage => &mut inverter.grid_voltage,
+                        Field::GridFrequency => &mut inverter.grid_frequency,
+                        Field::Temperature => &mut inverter.temperature,
+                        _ => &mut inverter.power,
+                    };
+                    (serial_to_string(inverter.serial), value)
+                })
+                .collect()
+        }
+        Field::PortVoltage | Field::PortCurrent | Field::PortPower => state
+            .ports
+            .iter_mut()
+            .map(|port| {
+                let source = format!("{}/{}", serial_to_string(port.inverter_serial), port.port);
+                let value = match field {
+                    Field::PortVoltage => &mut port.voltage,
+                    Field::PortCurrent => &mut port.current,
+                    _ => &mut port.power,
+                };
+                (source, value)
+            })
+            .collect(),
+    }
+}
+
+/// `Filter` checks the readings of one DTU against the plausibility rules.
+///
+/// The rates of change are computed from the last plausible value of each
+/// field and the time it was read, so it has to see every reading of the DTU in order.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    config: PlausibilityConfig,
+    previous: HashMap<(Field, String), (f32, DateTime<Utc>)>,
+}
+
+impl Filter {
+    pub fn new(config: PlausibilityConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Checks the reading, clamps values and flags it according to the policies.
+    ///
+    /// Returns false if the reading has to be dropped. Stale readings are not checked again.
+    pub fn apply(&mut self, reading: &mut Reading) -> bool {
+        if reading.stale {
+            return true;
+        }
+        let violations = self.check(&mut reading.state);
+        for violation in &violations {
+            let message = format!(
+                "{}: implausible {:?} {} of {} ({:?}), {:?}",
+                reading.inverter,
+                violation.field,
+                violation.value,
+                violation.source,
+                violation.check,
+                violation.policy
+            );
+            match violation.policy {
+                Policy::Clamp => info!("{message}"),
+                Policy::Drop | Policy::Flag => warn!("{message}"),
+            }
+        }
+        if violations
+            .iter()
+            .any(|violation| violation.policy == Policy::Drop)
+        {
+            return false;
+        }
+        reading.violations = violations
+            .into_iter()
+            .filter(|violation| violation.policy == Policy::Flag)
+            .collect();
+        self.remember(reading);
+        true
+    }
+
+    // checks all rules, values are clamped right away so later checks see them
+    fn check(&self, state: &mut DtuState) -> Vec<Violation> {
+        let time = state.time;
+        let mut violations = Vec::new();
+        for rule in &self.config.rules {
+            let policy = rule.policy.unwrap_or(self.config.policy);
+            for (source, value) in values_mut(state, rule.field) {
+                let mut low = rule.min.unwrap_or(f32::NEG_INFINITY);
+                let mut high = rule.max.unwrap_or(f32::INFINITY);
+                if !(low..=high).contains(value) {
+                    violations.push(Violation {
+                        field: rule.field,
+                        source: source.clone(),
+                        check: Check::Range,
+                        value: *value,
+                        policy,
+                    });
+                } else if let (Some(max_rate), Some((previous, previous_time))) = (
+                    rule.max_rate,
+                    self.previous.get(&(rule.field, source.clone())),
+                ) {
+                    // the window widens with the time since the last plausible value
+                    let seconds = (time - *previous_time).num_milliseconds() as f32 / 1000.;
+                    if seconds <= 0. {
+                        continue;
+                    }
+                    low = low.max(previous - max_rate * seconds);
+                    high = high.min(previous + max_rate * seconds);
+                    if !(low..=high).contains(value) {
+                        violations.push(Violation {
+                            field: rule.field,
+                            source,
+                            check: Check::Rate,
+                            value: *value,
+                            policy,
+                        });
+                    }
+                }
+                if policy == Policy::Clamp && low <= high {
+                    *value = value.clamp(low, high);
+                }
+            }
+        }
+        if let Some(ratio) = self.config.max_ac_dc_ratio {
+            let policy = self.config.ac_dc_policy.unwrap_or(self.config.policy);
+            for inverter in &mut state.inverters {
+                let dc_power: f32 = state
+                    .ports
+                    .iter()
+                    .filter(|port| port.inverter_serial == inverter.serial)
+                    .map(|port| port.power)
+                    .sum();
+                if inverter.power > dc_power * ratio {
+                    violations.push(Violation {
+                        field: Field::InverterPower,
+                        source: serial_to_string(inverter.serial),
+                        check: Check::CrossCheck,
+                        value: inverter.power,
+                        policy,
+                    });
+                    if policy == Policy::Clamp {
+                        // the power of the DTU adds up that of its inverters, it drops alike
+                        state.power -= inverter.power - dc_power * ratio;
+                        inverter.power = dc_power * ratio;
+                    }
+                }
+            }
+        }
+        violations
+    }
+
+    // flagged values are not remembered, the rate of the next value is checked against the last plausible one
+    fn remember(&mut self, reading: &mut Reading) {
+        let time = reading.state.time;
+        for rule in &self.config.rules {
+            if rule.max_rate.is_none() {
+                continue;
+            }
+            for (source, value) in values_mut(&mut reading.state, rule.field) {
+                let flagged = reading
+                    .violations
+                    .iter()
+                    .any(|violation| violation.field == rule.field && violation.source == source);
+                if !flagged {
+                    self.previous.insert((rule.field, source), (*value, time));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{noon, StateBuilder};
+    use chrono::Duration;
+
+    fn reading(seconds: i64, temperature: f32, port_power: f32, inverter_power: f32) -> Reading {
+        let mut reading = StateBuilder::new(noon() + Duration::seconds(seconds))
+            .power(inverter_power)
+            .port(port_power)
+            .reading();
+        let inverter = &mut reading.state.inverters[0];
+        inverter.grid_voltage = 230.;
+        inverter.temperature = temperature;
+        reading
+    }
+
+    fn filter(config: &str) -> Filter {
+        Filter::new(toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn policies() {
+        let mut filter = filter(
+            r#"
+            policy = "drop"
+            max_ac_dc_ratio = 1.0
+
+            [[rules]]
+            field = "temperature"
+            min = -40
+            max = 100
+
+            [[rules]]
+            field = "port_power"
+            max = 500
+            max_rate = 10
+            policy = "clamp"
+
+            [[rules]]
+            field = "grid_voltage"
+            min = 180
+            policy = "flag"
+            "#,
+        );
+        let mut first = reading(0, 40., 300., 280.);
+        assert!(filter.apply(&mut first));
+        assert!(first.violations.is_empty());
+
+        // the DTU reports its minimum value now and then
+        assert!(!filter.apply(&mut reading(30, -3276.8, 300., 280.)));
+        // the AC power of an inverter cannot exceed its DC power
+        assert!(!filter.apply(&mut reading(30, 40., 300., 400.)));
+
+        // 10 W per second allow for 300 W more after 30 s
+        let mut jump = reading(30, 40., 900., 280.);
+        assert!(filter.apply(&mut jump));
+        assert_eq!(jump.state.ports[0].power, 500.);
+        assert!(jump.violations.is_empty());
+
+        let mut low_voltage = reading(60, 40., 500., 280.);
+        low_voltage.state.inverters[0].grid_voltage = 0.;
+        assert!(filter.apply(&mut low_voltage));
+        assert_eq!(low_voltage.state.inverters[0].grid_voltage, 0.);
+        let violation = &low_voltage.violations[0];
+        assert_eq!(
+            (violation.field, violation.check, violation.policy),
+            (Field::GridVoltage, Check::Range, Policy::Flag)
+        );
+        assert_eq!(violation.source, "114400000001");
+    }
+
+    #[test]
+    fn flagged_rate_recovers() {
+        let mut filter = filter(
+            r#"
+            policy = "flag"
+            max_ac_dc_ratio = 1.0
+            ac_dc_policy = "clamp"
+
+            [[rules]]
+            field = "port_power"
+            max_rate = 10
+            "#,
+        );
+        assert!(filter.apply(&mut reading(0, 40., 100., 90.)));
+
+        // a cloud edge, more than 10 W per second
+        let mut jump = reading(30, 40., 700., 650.);
+        assert!(filter.apply(&mut jump));
+        assert_eq!(jump.violations[0].check, Check::Rate);
+
+        // the window around the last plausible value widens until the new level fits into it
+        for seconds in [60, 90] {
+            let mut later = reading(seconds, 40., 700., 650.);
+            assert!(filter.apply(&mut later));
+            assert!(later.violations.is_empty());
+        }
+
+        // the cross-check has its own policy
+        let mut high_ac = reading(120, 40., 700., 800.);
+        assert!(filter.apply(&mut high_ac));
+        assert!(high_ac.violations.is_empty());
+        assert_eq!(high_ac.state.inverters[0].power, 700.);
+        assert_eq!(high_ac.state.power, 700.);
+    }
+}
@@ -0,0 +1,235 @@
+use crc16::{State, MODBUS};
+use protobuf::Message;
+use std::fmt;
+use std::io::Read;
+
+// Every frame starts with a 10 byte header:
+// magic (2 bytes), command (2), sequence (2), CRC16 of the payload (2), total length (2)
+pub const HEADER_LENGTH: usize = 10;
+const MAGIC: [u8; 2] = *b"HM";
+
+/// `Command` lists the commands known to be understood by the DTU on port 10081.
+///
+/// Requests of the app carry the command ID with a leading 0xa3, the DTU
+/// answers with the 0xa2 counterpart of the same command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    AppInfoData,
+    HeartBeat,
+    RealData,
+    WarnInfo,
+    Command,
+    CommandStatus,
+    DevConfigFetch,
+    DevConfigPut,
+    GetConfig,
+    SetConfig,
+    RealDataNew,
+    AutoSearch,
+    NetworkInfo,
+    AppGetHistPower,
+}
+
+const COMMAND_IDS: [(Command, u8); 14] = [
+    (Command::AppInfoData, 0x01),
+    (Command::HeartBeat, 0x02),
+    (Command::RealData, 0x03),
+    (Command::WarnInfo, 0x04),
+    (Command::Command, 0x05),
+    (Command::CommandStatus, 0x06),
+    (Command::DevConfigFetch, 0x07),
+    (Command::DevConfigPut, 0x08),
+    (Command::GetConfig, 0x09),
+    (Command::SetConfig, 0x10),
+    (Command::RealDataNew, 0x11),
+    (Command::AutoSearch, 0x13),
+    (Command::NetworkInfo, 0x14),
+    (Command::AppGetHistPower, 0x15),
+];
+
+impl Command {
+    fn id(&self) -> u8 {
+        COMMAND_IDS
+            .iter()
+            .find(|(command, _)| command == self)
+            .map(|(_, id)| *id)
+            .expect("every command has an id")
+    }
+
+    pub fn request_id(&self) -> [u8; 2] {
+        [0xa3, self.id()]
+    }
+
+    pub fn response_id(&self) -> [u8; 2] {
+        [0xa2, self.id()]
+    }
+
+    /// Looks up the command of a request or response ID.
+    pub fn from_id(id: [u8; 2]) -> Option<Command> {
+        if id[0] != 0xa2 && id[0] != 0xa3 {
+            return None;
+        }
+        COMMAND_IDS
+            .iter()
+            .find(|(_, command_id)| *command_id == id[1])
+            .map(|(command, _)| *command)
+    }
+}
+
+/// Violations of the frame layout.
+#[derive(Debug)]
+pub enum FrameError {
+    Magic([u8; 2]),
+    Command {
+        expected: [u8; 2],
+        received: [u8; 2],
+    },
+    Sequence {
+        expected: u16,
+        received: u16,
+    },
+    Length(u16),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Magic(magic) => write!(f, "invalid frame magic {magic:02x?}"),
+            FrameError::Command { expected, received } => write!(
+                f,
+                "unexpected command {received:02x?}, expected {expected:02x?}"
+            ),
+            FrameError::Sequence { expected, received } => write!(
+                f,
+                "unexpected sequence number {received}, expected {expected}"
+            ),
+            FrameError::Length(length) => write!(f, "invalid frame length {length}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    Frame(FrameError),
+    Crc { expected: u16, calculated: u16 },
+    Decode(protobuf::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "{e}"),
+            ProtocolError::Frame(e) => write!(f, "{e}"),
+            ProtocolError::Crc {
+                expected,
+                calculated,
+            } => write!(
+                f,
+                "CRC mismatch, frame declares {expected:#06x}, payload has {calculated:#06x}"
+            ),
+            ProtocolError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// `Frame` is a single message exchanged with the DTU, i.e. a header plus
+/// a serialized protobuf message as payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub command: [u8; 2],
+    pub sequence: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new<M: Message>(command: [u8; 2], sequence: u16, message: &M) -> Self {
+        Self {
+            command,
+            sequence,
+            payload: message.write_to_bytes().expect("serialize to bytes"),
+        }
+    }
+
+    pub fn request<M: Message>(command: Command, sequence: u16, message: &M) -> Self {
+        Self::new(command.request_id(), sequence, message)
+    }
+
+    pub fn response<M: Message>(command: Command, sequence: u16, message: &M) -> Self {
+        Self::new(command.response_id(), sequence, message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let crc16 = State::<MODBUS>::calculate(&self.payload);
+        let len = self.payload.len() as u16 + HEADER_LENGTH as u16;
+
+        let mut message = Vec::with_capacity(len as usize);
+        message.extend_from_slice(&MAGIC);
+        message.extend_from_slice(&self.command);
+        message.extend_from_slice(&self.sequence.to_be_bytes());
+        message.extend_from_slice(&crc16.to_be_bytes());
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(&self.payload);
+        message
+    }
+
+    /// Reads a complete frame and validates magic, length and checksum.
+    ///
+    /// Frames may span several TCP segments, so the header is read first and
+    /// then reading continues until the declared length has arrived.
+    pub fn read(reader: &mut impl Read) -> Result<Self, ProtocolError> {
+        let mut header = [0u8; HEADER_LENGTH];
+        reader.read_exact(&mut header).map_err(ProtocolError::Io)?;
+
+        let magic = [header[0], header[1]];
+        if magic != MAGIC {
+            return Err(ProtocolError::Frame(FrameError::Magic(magic)));
+        }
+        let command = [header[2], header[3]];
+        let sequence = u16::from_be_bytes([header[4], header[5]]);
+        let crc16 = u16::from_be_bytes([header[6], header[7]]);
+        let length = u16::from_be_bytes([header[8], header[9]]);
+        if (length as usize) < HEADER_LENGTH {
+            return Err(ProtocolError::Frame(FrameError::Length(length)));
+        }
+
+        let mut payload = vec![0u8; length as usize - HEADER_LENGTH];
+        reader.read_exact(&mut payload).map_err(ProtocolError::Io)?;
+
+        let calculated = State::<MODBUS>::calculate(&payload);
+        if calculated != crc16 {
+            return Err(ProtocolError::Crc {
+                expected: crc16,
+                calculated,
+            });
+        }
+        Ok(Self {
+            command,
+            sequence,
+            payload,
+        })
+    }
+
+    /// Checks that this frame answers the request with the given command and sequence.
+    pub fn check_response(&self, command: Command, sequence: u16) -> Result<(), ProtocolError> {
+        if self.command != command.response_id() {
+            return Err(ProtocolError::Frame(FrameError::Command {
+                expected: command.response_id(),
+                received: self.command,
+            }));
+        }
+        if self.sequence != sequence {
+            return Err(ProtocolError::Frame(FrameError::Sequence {
+                expected: sequence,
+                received: self.sequence,
+            }));
+        }
+        Ok(())
+    }
+
+    pub fn decode<M: Message>(&self) -> Result<M, ProtocolError> {
+        M::parse_from_bytes(&self.payload).map_err(ProtocolError::Decode)
+    }
+}
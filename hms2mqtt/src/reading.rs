@@ -0,0 +1,462 @@
+use crate::derived::DerivedMetrics;
+use crate::plausibility::Violation;
+use crate::protos::hoymiles::RealData::{self, HMSStateResponse};
+use crate::protos::hoymiles::RealDataNew::{MeterMO, PvMO, RealDataNewReqDTO, SGSMO, TGSMO};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+
+// the DTU transmits fixed-point numbers, these undo their factors
+fn tenths(value: i32) -> f32 {
+    value as f32 / 10.
+}
+
+fn hundredths(value: i32) -> f32 {
+    value as f32 / 100.
+}
+
+fn thousandths(value: i32) -> f32 {
+    value as f32 / 1000.
+}
+
+// serial numbers are printed as hex on the label of the inverter
+pub fn serial_to_string(serial: i64) -> String {
+    format!("{serial:x}")
+}
+
+fn timestamp(epoch: i32) -> DateTime<Utc> {
+    Utc.timestamp_opt(epoch as i64, 0)
+        .single()
+        .unwrap_or_default()
+}
+
+/// `PortState` is a reading of a single PV input.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PortState {
+    /// Serial number of the inverter the port belongs to
+    pub inverter_serial: i64,
+    /// Number of the port on its inverter, starting at 1
+    pub port: i32,
+    /// [V]
+    pub voltage: f32,
+    /// [A]
+    pub current: f32,
+    /// [W]
+    pub power: f32,
+    /// [Wh]
+    pub energy_total: f64,
+    /// [Wh]
+    pub daily_yield: f64,
+    /// Fields whose meaning is not known yet, by their name in the message
+    pub unknown_fields: BTreeMap<&'static str, i32>,
+}
+
+// names of the phases and of the lines between them, in the order of `InverterState::phases`
+pub const PHASE_NAMES: [&str; 3] = ["a", "b", "c"];
+pub const LINE_NAMES: [&str; 3] = ["ab", "bc", "ca"];
+
+/// `PhaseState` is a reading of a single phase of the grid connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PhaseState {
+    /// [V]
+    pub voltage: f32,
+    /// [A]
+    pub current: f32,
+    /// [W], voltage times current, as the DTU does not report it
+    pub power: f32,
+    /// Voltage to the next phase, i.e. AB, BC and CA, [V]
+    pub line_voltage: f32,
+}
+
+/// `InverterStatus` holds the status flags of an inverter whose meaning is
+/// known. Flags the firmware does not report are None.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct InverterStatus {
+    /// The inverter feeds into the grid
+    pub producing: bool,
+    /// The DTU reaches the inverter
+    pub link: Option<bool>,
+    /// The power limit is below 100%
+    pub limited: Option<bool>,
+    /// The inverter has active alarms
+    pub alarm_active: Option<bool>,
+}
+
+impl InverterStatus {
+    fn new(power: f32, power_limit: Option<f32>) -> Self {
+        Self {
+            producing: power > 0.,
+            limited: power_limit.map(|power_limit| power_limit < 100.),
+            ..Default::default()
+        }
+    }
+}
+
+/// `InverterState` is a reading of the AC side of a single inverter.
+///
+/// Fields that only newer firmware reports are None for readings fetched
+/// with the legacy command.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InverterState {
+    pub serial: i64,
+    /// Number of the inverter on its DTU, starting at 1
+    pub number: i32,
+    /// [V], the mean of all phases for three-phase inverters
+    pub grid_voltage: f32,
+    /// [Hz]
+    pub grid_frequency: f32,
+    /// [W]
+    pub power: f32,
+    /// [%], not reported by three-phase inverters
+    pub power_limit: Option<f32>,
+    /// [°C]
+    pub temperature: f32,
+    /// [var]
+    pub reactive_power: Option<f32>,
+    pub power_factor: Option<f32>,
+    /// Phases A, B and C of three-phase inverters, empty for single-phase ones
+    pub phases: Vec<PhaseState>,
+    pub status: InverterStatus,
+    /// Fields whose meaning is not known yet, by their name in the message
+    pub unknown_fields: BTreeMap<&'static str, i32>,
+}
+
+/// `MeterState` is a reading of an energy meter attached to the DTU.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MeterState {
+    pub serial: i64,
+    pub device_type: i32,
+    /// [W]
+    pub power: f32,
+    /// Power of phases A, B and C, [W]
+    pub phase_power: [f32; 3],
+    pub power_factor: f32,
+    /// [Wh]
+    pub energy_exported: f64,
+    /// [Wh]
+    pub energy_imported: f64,
+}
+
+/// `DtuState` is a reading of a DTU and its inverters in SI units, no
+/// matter which command it was fetched with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DtuState {
+    pub dtu_sn: String,
+    /// When the DTU took the reading
+    pub time: DateTime<Utc>,
+    /// [W]
+    pub power: f32,
+    /// [Wh]
+    pub daily_yield: f64,
+    pub inverters: Vec<InverterState>,
+    pub ports: Vec<PortState>,
+    pub meters: Vec<MeterState>,
+}
+
+impl From<&RealData::InverterState> for InverterState {
+    fn from(inverter: &RealData::InverterState) -> Self {
+        let power = tenths(inverter.pv_current_power);
+        let power_limit = Some(tenths(inverter.power_limit));
+        Self {
+            serial: inverter.inv_id,
+            number: inverter.port_id,
+            grid_voltage: tenths(inverter.grid_voltage),
+            grid_frequency: hundredths(inverter.grid_freq),
+            power,
+            power_limit,
+            temperature: tenths(inverter.temperature),
+            status: InverterStatus::new(power, power_limit),
+            unknown_fields: BTreeMap::from([
+                ("unknown1", inverter.unknown1),
+                ("unknown3", inverter.unknown3),
+                ("unknown4", inverter.unknown4),
+                ("bit_field", inverter.bit_field),
+            ]),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&RealData::PortState> for PortState {
+    fn from(port: &RealData::PortState) -> Self {
+        Self {
+            inverter_serial: port.pv_sn,
+            port: port.pv_port,
+            voltage: tenths(port.pv_vol),
+            current: hundredths(port.pv_cur),
+            power: tenths(port.pv_power),
+            energy_total: port.pv_energy_total as f64,
+            daily_yield: port.pv_daily_yield as f64,
+            unknown_fields: BTreeMap::from([("bitfield", port.bitfield)]),
+        }
+    }
+}
+
+impl From<&HMSStateResponse> for DtuState {
+    fn from(response: &HMSStateResponse) -> Self {
+        Self {
+            dtu_sn: response.dtu_sn.clone(),
+            time: timestamp(response.time),
+            power: tenths(response.pv_current_power),
+            daily_yield: response.pv_daily_yield as f64,
+            inverters: response
+                .inverter_state
+                .iter()
+                .map(InverterState::from)
+                .collect(),
+            ports: response.port_state.iter().map(PortState::from).collect(),
+            meters: Vec::new(),
+        }
+    }
+}
+
+impl From<&SGSMO> for InverterState {
+    fn from(inverter: &SGSMO) -> Self {
+        let power = tenths(inverter.active_power);
+        let power_limit = Some(tenths(inverter.power_limit));
+        Self {
+            serial: inverter.serial_number,
+            grid_voltage: tenths(inverter.voltage),
+            grid_frequency: hundredths(inverter.frequency),
+            power,
+            power_limit,
+            temperature: tenths(inverter.temperature),
+            reactive_power: Some(tenths(inverter.reactive_power)),
+            power_factor: Some(thousandths(inverter.power_factor)),
+            status: InverterStatus {
+                link: Some(inverter.link_status != 0),
+                alarm_active: Some(inverter.warning_number > 0),
+                ..InverterStatus::new(power, power_limit)
+            },
+            unknown_fields: BTreeMap::from([
+                ("crc_checksum", inverter.crc_checksum),
+                ("modulation_index_signal", inverter.modulation_index_signal),
+            ]),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&TGSMO> for InverterState {
+    fn from(inverter: &TGSMO) -> Self {
+        let phase = |voltage: i32, current: i32, line_voltage: i32| PhaseState {
+            voltage: tenths(voltage),
+            current: hundredths(current),
+            power: tenths(voltage) * hundredths(current),
+            line_voltage: tenths(line_voltage),
+        };
+        let phases = vec![
+            phase(
+                inverter.voltage_phase_A,
+                inverter.current_phase_A,
+                inverter.voltage_line_AB,
+            ),
+            phase(
+                inverter.voltage_phase_B,
+                inverter.current_phase_B,
+                inverter.voltage_line_BC,
+            ),
+            phase(
+                inverter.voltage_phase_C,
+                inverter.current_phase_C,
+                inverter.voltage_line_CA,
+            ),
+        ];
+        let power = tenths(inverter.active_power);
+        Self {
+            serial: inverter.serial_number,
+            grid_voltage: phases.iter().map(|phase| phase.voltage).sum::<f32>() / 3.,
+            grid_frequency: hundredths(inverter.frequency),
+            power,
+            temperature: tenths(inverter.temperature),
+            reactive_power: Some(tenths(inverter.reactive_power)),
+            power_factor: Some(thousandths(inverter.power_factor)),
+            phases,
+            status: InverterStatus {
+                link: Some(inverter.link_status != 0),
+                alarm_active: Some(inverter.warning_number > 0),
+                ..InverterStatus::new(power, None)
+            },
+            unknown_fields: BTreeMap::from([
+                ("crc_checksum", inverter.crc_checksum),
+                ("modulation_index_signal", inverter.modulation_index_signal),
+            ]),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&PvMO> for PortState {
+    fn from(port: &PvMO) -> Self {
+        Self {
+            inverter_serial: port.serial_number,
+            port: port.port_number,
+            voltage: tenths(port.voltage),
+            current: hundredths(port.current),
+            power: tenths(port.power),
+            energy_total: port.energy_total as f64,
+            daily_yield: port.energy_daily as f64,
+            unknown_fields: BTreeMap::from([("error_code", port.error_code)]),
+        }
+    }
+}
+
+impl From<&MeterMO> for MeterState {
+    fn from(meter: &MeterMO) -> Self {
+        Self {
+            serial: meter.serial_number,
+            device_type: meter.device_type,
+            power: tenths(meter.phase_total_power),
+            phase_power: [
+                tenths(meter.phase_A_power),
+                tenths(meter.phase_B_power),
+                tenths(meter.phase_C_power),
+            ],
+            power_factor: thousandths(meter.power_factor_total),
+            energy_exported: meter.energy_total_power as f64,
+            energy_imported: meter.energy_total_consumed as f64,
+        }
+    }
+}
+
+impl From<&RealDataNewReqDTO> for DtuState {
+    fn from(response: &RealDataNewReqDTO) -> Self {
+        let mut inverters: Vec<InverterState> = response
+            .sgs_data
+            .iter()
+            .map(InverterState::from)
+            .chain(response.tgs_data.iter().map(InverterState::from))
+            .collect();
+        // the newer message has no port ID, numbering the inverters keeps the IDs of the legacy one
+        for (i, inverter) in inverters.iter_mut().enumerate() {
+            inverter.number = i as i32 + 1;
+        }
+        Self {
+            dtu_sn: response.device_serial_number.clone(),
+            time: timestamp(response.timestamp),
+            power: tenths(response.dtu_power),
+            daily_yield: response.dtu_daily_energy as f64,
+            inverters,
+            ports: response.pv_data.iter().map(PortState::from).collect(),
+            meters: response.meter_data.iter().map(MeterState::from).collect(),
+        }
+    }
+}
+
+impl DtuState {
+    /// Whether ports and inverters of several inverters are mixed in the reading,
+    /// which then have to be told apart by the serial number of their inverter.
+    pub fn has_several_inverters(&self) -> bool {
+        let serials: BTreeSet<i64> = self
+            .inverters
+            .iter()
+            .map(|inverter| inverter.serial)
+            .chain(self.ports.iter().map(|port| port.inverter_serial))
+            .collect();
+        serials.len() > 1
+    }
+
+    /// The power limit of the DTU, which is set for all of its inverters at once.
+    ///
+    /// None if the inverters report different limits, e.g. after some of them
+    /// were limited in the app.
+    pub fn power_limit(&self) -> Option<f32> {
+        let mut limits = self
+            .inverters
+            .iter()
+            .filter_map(|inverter| inverter.power_limit);
+        let limit = limits.next()?;
+        limits.all(|other| other == limit).then_some(limit)
+    }
+
+    /// The fields whose meaning is not known yet, e.g. to be published for
+    /// the community to help decoding them.
+    pub fn unknown_fields(&self) -> serde_json::Value {
+        json!({
+            "inverters": self
+                .inverters
+                .iter()
+                .map(|inverter| json!({
+                    "inv_id": serial_to_string(inverter.serial),
+                    "fields": inverter.unknown_fields,
+                }))
+                .collect::<Vec<_>>(),
+            "ports": self
+                .ports
+                .iter()
+                .map(|port| json!({
+                    "pv_sn": serial_to_string(port.inverter_serial),
+                    "pv_port": port.port,
+                    "fields": port.unknown_fields,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// `Reading` is a single state update of an inverter, tagged with the name
+/// of the configured inverter it was fetched from.
+pub struct Reading {
+    pub inverter: String,
+    pub state: DtuState,
+    /// Values computed from the state, empty until a `Derivation` was applied
+    pub metrics: DerivedMetrics,
+    /// Checks the reading failed that are flagged rather than dropped or clamped
+    pub violations: Vec<Violation>,
+    /// The reading repeats the previous one, e.g. because the DTU answered from its cache.
+    pub stale: bool,
+}
+
+impl Reading {
+    pub fn new(inverter: &str, state: DtuState) -> Self {
+        Self {
+            inverter: inverter.to_string(),
+            state,
+            metrics: DerivedMetrics::default(),
+            violations: Vec::new(),
+            stale: false,
+        }
+    }
+
+    /// Flags the reading as a repetition of the previous one.
+    pub fn stale(mut self) -> Self {
+        self.stale = true;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{noon, port, StateBuilder, SERIAL};
+
+    #[test]
+    fn several_inverters_are_told_apart() {
+        let single = StateBuilder::new(noon())
+            .port(0.)
+            .port(0.)
+            .port(0.)
+            .port(0.)
+            .build();
+        assert!(!single.has_several_inverters());
+
+        let mut several = single.clone();
+        several.ports.push(port(SERIAL + 1, 1));
+        assert!(several.has_several_inverters());
+    }
+
+    #[test]
+    fn power_limit_of_the_dtu() {
+        // the limit is set for the whole DTU, it is only known while all inverters agree
+        let mut state = StateBuilder::new(noon()).build();
+        let limited = |power_limit| InverterState {
+            power_limit,
+            ..Default::default()
+        };
+        state.inverters = vec![limited(Some(50.)), limited(Some(50.)), limited(None)];
+        assert_eq!(state.power_limit(), Some(50.));
+        state.inverters[1].power_limit = Some(80.);
+        assert_eq!(state.power_limit(), None);
+    }
+}
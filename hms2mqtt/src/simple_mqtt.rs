@@ -1,14 +1,18 @@
 use crate::{
+    alarm::Alarm,
+    analysis::Underperformance,
+    command::{CommandResponse, CommandStatus, InverterCommand, PowerLimit},
+    device_info::DeviceInfo,
+    dtu_config::DtuConfig,
+    history::PowerHistory,
     metric_collector::MetricCollector,
     mqtt_config::MqttConfig,
     mqtt_wrapper::{MqttWrapper, QoS},
-    protos::hoymiles::RealData::HMSStateResponse,
+    reading::{serial_to_string, Reading, LINE_NAMES, PHASE_NAMES},
 };
 
-use chrono::prelude::DateTime;
 use chrono::Local;
 use log::{debug, warn};
-use std::time::{Duration, UNIX_EPOCH};
 
 pub struct SimpleMqtt<MQTT: MqttWrapper> {
     client: MQTT,
@@ -16,72 +20,368 @@ pub struct SimpleMqtt<MQTT: MqttWrapper> {
 
 impl<MQTT: MqttWrapper> SimpleMqtt<MQTT> {
     pub fn new(config: &MqttConfig) -> Self {
-        let client = MQTT::new(config, "-sm");
+        let mut client = MQTT::new(config, "-sm");
+        // commands are accepted for unnamed and named inverters alike
+        for topic in ["hms800wt2/+/set", "hms800wt2/+/+/set"] {
+            if let Err(e) = client.subscribe(topic, QoS::AtLeastOnce) {
+                warn!("subscription to {topic} failed: {e}");
+            }
+        }
         Self { client }
     }
+
+    fn publish_value(&mut self, inverter: &str, topic: &str, payload: String) {
+        let topic = format!("{}/{topic}", base_topic(inverter));
+        if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
+            warn!("mqtt error: {e:?}")
+        }
+    }
+}
+
+/// Translates a message on a command topic, i.e. `hms800wt2/[<inverter>/]<command>/set`,
+/// into the addressed inverter and its command.
+fn parse_command(topic: &str, payload: &[u8]) -> Option<(String, InverterCommand)> {
+    let path = topic.strip_prefix("hms800wt2/")?.strip_suffix("/set")?;
+    let (inverter, command) = path.rsplit_once('/').unwrap_or(("", path));
+    let payload = String::from_utf8_lossy(payload);
+    match InverterCommand::parse(command, &payload) {
+        Ok(command) => Some((inverter.to_string(), command)),
+        Err(e) => {
+            warn!("ignoring message on command topic {topic}: {e}");
+            None
+        }
+    }
+}
+
+// Readings of unnamed inverters are published below the top-level topic for
+// backwards compatibility, named inverters get their own sub-tree.
+fn base_topic(inverter: &str) -> String {
+    if inverter.is_empty() {
+        "hms800wt2".to_string()
+    } else {
+        format!("hms800wt2/{inverter}")
+    }
 }
 
 impl<MQTT: MqttWrapper> MetricCollector for SimpleMqtt<MQTT> {
-    fn publish(&mut self, hms_state: &HMSStateResponse) {
-        debug!("{hms_state}");
+    fn publish(&mut self, reading: &Reading) {
+        // a repeated reading is only marked as such, the values stay as they were
+        self.publish_value(&reading.inverter, "stale", reading.stale.to_string());
+        if reading.stale {
+            return;
+        }
+        let hms_state = &reading.state;
+        let metrics = &reading.metrics;
+        debug!("{hms_state:?}");
 
-        let d = UNIX_EPOCH + Duration::from_secs(hms_state.time as u64);
-        let datetime = DateTime::<Local>::from(d);
+        let datetime = hms_state.time.with_timezone(&Local);
         let inverter_local_time = datetime.format("%Y-%m-%d %H:%M:%S.%f").to_string();
 
-        let pv_current_power = hms_state.pv_current_power as f32 / 10.;
-        let pv_daily_yield = hms_state.pv_daily_yield;
-        let pv_grid_voltage = hms_state.inverter_state[0].grid_voltage as f32 / 10.;
-        let pv_grid_freq = hms_state.inverter_state[0].grid_freq as f32 / 100.;
-        let pv_inv_temperature = hms_state.inverter_state[0].temperature as f32 / 10.;
-        let pv_port1_voltage = hms_state.port_state[0].pv_vol as f32 / 10.;
-        let pv_port1_curr = hms_state.port_state[0].pv_cur as f32 / 100.;
-        let pv_port1_power = hms_state.port_state[0].pv_power as f32 / 10.;
-        let pv_port1_energy = hms_state.port_state[0].pv_energy_total as f32;
-        let pv_port1_daily_yield = hms_state.port_state[0].pv_daily_yield as f32;
-        let pv_port2_voltage = hms_state.port_state[1].pv_vol as f32 / 10.;
-        let pv_port2_curr = hms_state.port_state[1].pv_cur as f32 / 100.;
-        let pv_port2_power = hms_state.port_state[1].pv_power as f32 / 10.;
-        let pv_port2_energy = hms_state.port_state[1].pv_energy_total as f32;
-        let pv_port2_daily_yield = hms_state.port_state[1].pv_daily_yield as f32;
-
-        // TODO: this section bears a lot of repetition. Investigate if there's a more idiomatic way to get the same result, perhaps using a macro
-        let topic_payload_pairs = [
-            ("hms800wt2/inverter_local_time", inverter_local_time),
-            ("hms800wt2/pv_current_power", pv_current_power.to_string()),
-            ("hms800wt2/pv_daily_yield", pv_daily_yield.to_string()),
-            ("hms800wt2/pv_current_power", pv_current_power.to_string()),
-            ("hms800wt2/pv_daily_yield", pv_daily_yield.to_string()),
-            ("hms800wt2/pv_grid_voltage", pv_grid_voltage.to_string()),
-            ("hms800wt2/pv_grid_freq", pv_grid_freq.to_string()),
+        let mut topic_payload_pairs = vec![
+            ("inverter_local_time".to_string(), inverter_local_time),
+            ("pv_current_power".to_string(), hms_state.power.to_string()),
+            (
+                "pv_daily_yield".to_string(),
+                hms_state.daily_yield.to_string(),
+            ),
+        ];
+        // on DTUs with several inverters, each one and its ports get a sub-tree named by serial number
+        let several = hms_state.has_several_inverters();
+        let prefix = |serial: i64| {
+            if several {
+                format!("{}/", serial_to_string(serial))
+            } else {
+                String::new()
+            }
+        };
+        let inverter_count = if several { usize::MAX } else { 1 };
+        for (i, inverter) in hms_state.inverters.iter().enumerate().take(inverter_count) {
+            let prefix = prefix(inverter.serial);
+            if let Some(inverter_metrics) = metrics.inverters.get(i) {
+                if let Some(efficiency) = inverter_metrics.efficiency {
+                    topic_payload_pairs
+                        .push((format!("{prefix}pv_inv_efficiency"), efficiency.to_string()));
+                }
+                if let Some(lifetime_energy) = inverter_metrics.lifetime_energy {
+                    topic_payload_pairs.push((
+                        format!("{prefix}pv_inv_lifetime_energy"),
+                        lifetime_energy.to_string(),
+                    ));
+                }
+            }
+            topic_payload_pairs.extend([
+                (
+                    format!("{prefix}pv_grid_voltage"),
+                    inverter.grid_voltage.to_string(),
+                ),
+                (
+                    format!("{prefix}pv_grid_freq"),
+                    inverter.grid_frequency.to_string(),
+                ),
+                (
+                    format!("{prefix}pv_inv_temperature"),
+                    inverter.temperature.to_string(),
+                ),
+            ]);
+            if let Some(power_limit) = inverter.power_limit {
+                topic_payload_pairs.push((format!("{prefix}power_limit"), power_limit.to_string()));
+            }
+            // only reported by newer firmware
+            if let Some(reactive_power) = inverter.reactive_power {
+                topic_payload_pairs.push((
+                    format!("{prefix}pv_reactive_power"),
+                    reactive_power.to_string(),
+                ));
+            }
+            if let Some(power_factor) = inverter.power_factor {
+                topic_payload_pairs
+                    .push((format!("{prefix}pv_power_factor"), power_factor.to_string()));
+            }
+            let status = &inverter.status;
+            let flags = [
+                ("pv_inv_producing", Some(status.producing)),
+                ("pv_inv_link", status.link),
+                ("pv_inv_limited", status.limited),
+                ("pv_inv_alarm_active", status.alarm_active),
+            ];
+            for (topic, value) in flags {
+                if let Some(value) = value {
+                    topic_payload_pairs.push((format!("{prefix}{topic}"), value.to_string()));
+                }
+            }
+            // three-phase inverters only
+            for ((phase, line), state) in PHASE_NAMES.iter().zip(LINE_NAMES).zip(&inverter.phases) {
+                topic_payload_pairs.extend([
+                    (
+                        format!("{prefix}pv_grid_phase_{phase}_voltage"),
+                        state.voltage.to_string(),
+                    ),
+                    (
+                        format!("{prefix}pv_grid_phase_{phase}_curr"),
+                        state.current.to_string(),
+                    ),
+                    (
+                        format!("{prefix}pv_grid_phase_{phase}_power"),
+                        state.power.to_string(),
+                    ),
+                    (
+                        format!("{prefix}pv_grid_line_{line}_voltage"),
+                        state.line_voltage.to_string(),
+                    ),
+                ]);
+            }
+        }
+        for (i, port) in hms_state.ports.iter().enumerate() {
+            let prefix = prefix(port.inverter_serial);
+            let n = port.port;
+            if let Some(port_metrics) = metrics.ports.get(i) {
+                if let Some(dc_share) = port_metrics.dc_share {
+                    topic_payload_pairs
+                        .push((format!("{prefix}pv_port{n}_dc_share"), dc_share.to_string()));
+                }
+                if let Some(specific_yield) = port_metrics.specific_yield {
+                    topic_payload_pairs.push((
+                        format!("{prefix}pv_port{n}_specific_yield"),
+                        specific_yield.to_string(),
+                    ));
+                }
+                if let Some(lifetime_energy) = port_metrics.lifetime_energy {
+                    topic_payload_pairs.push((
+                        format!("{prefix}pv_port{n}_lifetime_energy"),
+                        lifetime_energy.to_string(),
+                    ));
+                }
+                if let Some(underperforming) = port_metrics.underperforming {
+                    topic_payload_pairs.push((
+                        format!("{prefix}pv_port{n}_underperforming"),
+                        underperforming.to_string(),
+                    ));
+                }
+            }
+            topic_payload_pairs.extend([
+                (
+                    format!("{prefix}pv_port{n}_voltage"),
+                    port.voltage.to_string(),
+                ),
+                (format!("{prefix}pv_port{n}_curr"), port.current.to_string()),
+                (format!("{prefix}pv_port{n}_power"), port.power.to_string()),
+                (
+                    format!("{prefix}pv_port{n}_energy"),
+                    port.energy_total.to_string(),
+                ),
+                (
+                    format!("{prefix}pv_port{n}_daily_yield"),
+                    port.daily_yield.to_string(),
+                ),
+            ]);
+        }
+        // values derived from the reading, see `derived`
+        if let Some(efficiency) = metrics.efficiency {
+            topic_payload_pairs.push(("pv_efficiency".to_string(), efficiency.to_string()));
+        }
+        if let Some(specific_yield) = metrics.specific_yield {
+            topic_payload_pairs.push(("pv_specific_yield".to_string(), specific_yield.to_string()));
+        }
+        if let Some(peak) = metrics.peak_power {
+            let peak_time = peak.time.with_timezone(&Local);
+            topic_payload_pairs.extend([
+                ("pv_peak_power".to_string(), peak.power.to_string()),
+                (
+                    "pv_peak_power_time".to_string(),
+                    peak_time.format("%Y-%m-%d %H:%M:%S.%f").to_string(),
+                ),
+            ]);
+        }
+        // flagged readings are published as they are, see `plausibility`
+        topic_payload_pairs.extend([
+            (
+                "plausible".to_string(),
+                reading.violations.is_empty().to_string(),
+            ),
             (
-                "hms800wt2/pv_inv_temperature",
-                pv_inv_temperature.to_string(),
+                "violations".to_string(),
+                serde_json::to_string(&reading.violations).unwrap(),
             ),
-            ("hms800wt2/pv_port1_voltage", pv_port1_voltage.to_string()),
-            ("hms800wt2/pv_port1_curr", pv_port1_curr.to_string()),
-            ("hms800wt2/pv_port1_power", pv_port1_power.to_string()),
-            ("hms800wt2/pv_port1_energy", pv_port1_energy.to_string()),
+        ]);
+        // fields of unknown meaning, for the community to help decoding them
+        topic_payload_pairs.push(("raw".to_string(), hms_state.unknown_fields().to_string()));
+        if let Some(meter) = hms_state.meters.first() {
+            topic_payload_pairs.push(("meter_power".to_string(), meter.power.to_string()));
+        }
+
+        let base_topic = base_topic(&reading.inverter);
+        topic_payload_pairs
+            .into_iter()
+            .for_each(|(topic, payload)| {
+                let topic = format!("{base_topic}/{topic}");
+                if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
+                    warn!("mqtt error: {e:?}")
+                }
+            });
+    }
+
+    fn publish_config(&mut self, inverter: &str, config: &DtuConfig) {
+        debug!("{config:?}");
+
+        let base_topic = base_topic(inverter);
+        let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(config) else {
+            return;
+        };
+        fields.into_iter().for_each(|(key, value)| {
+            let topic = format!("{base_topic}/config/{key}");
+            let payload = match value {
+                serde_json::Value::String(s) => s,
+                value => value.to_string(),
+            };
+            if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
+                warn!("mqtt error: {e:?}")
+            }
+        });
+    }
+
+    fn publish_device_info(&mut self, inverter: &str, info: &DeviceInfo) {
+        debug!("{info:?}");
+
+        let base_topic = base_topic(inverter);
+        let mut topic_payload_pairs = vec![
+            ("info/dtu_sn".to_string(), info.dtu_sn.clone()),
             (
-                "hms800wt2/pv_port1_daily_yield",
-                pv_port1_daily_yield.to_string(),
+                "info/dtu_sw_version".to_string(),
+                info.dtu_sw_version.clone(),
             ),
-            ("hms800wt2/pv_port2_voltage", pv_port2_voltage.to_string()),
-            ("hms800wt2/pv_port2_curr", pv_port2_curr.to_string()),
-            ("hms800wt2/pv_port2_power", pv_port2_power.to_string()),
-            ("hms800wt2/pv_port2_energy", pv_port2_energy.to_string()),
             (
-                "hms800wt2/pv_port2_daily_yield",
-                pv_port2_daily_yield.to_string(),
+                "info/dtu_hw_version".to_string(),
+                info.dtu_hw_version.clone(),
             ),
         ];
+        for inverter in &info.inverters {
+            let serial = &inverter.serial;
+            topic_payload_pairs.extend([
+                (format!("info/{serial}/model"), inverter.model.clone()),
+                (
+                    format!("info/{serial}/sw_version"),
+                    inverter.sw_version.clone(),
+                ),
+                (
+                    format!("info/{serial}/hw_version"),
+                    inverter.hw_version.clone(),
+                ),
+            ]);
+        }
 
         topic_payload_pairs
             .into_iter()
             .for_each(|(topic, payload)| {
+                let topic = format!("{base_topic}/{topic}");
                 if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, true, payload) {
                     warn!("mqtt error: {e:?}")
                 }
             });
     }
+
+    fn publish_alarm(&mut self, inverter: &str, alarm: &Alarm) {
+        let topic = format!("{}/alarm", base_topic(inverter));
+        let payload = serde_json::to_string(alarm).unwrap();
+        // alarms are events rather than state, so they are not retained
+        if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, false, payload) {
+            warn!("mqtt error: {e:?}")
+        }
+    }
+
+    fn publish_underperformance(&mut self, inverter: &str, event: &Underperformance) {
+        let topic = format!("{}/underperformance", base_topic(inverter));
+        let payload = serde_json::to_string(event).unwrap();
+        // like alarms, these are events rather than state
+        if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, false, payload) {
+            warn!("mqtt error: {e:?}")
+        }
+    }
+
+    fn publish_history(&mut self, inverter: &str, history: &PowerHistory) {
+        let topic = format!("{}/history", base_topic(inverter));
+        let payload = serde_json::to_string(history).unwrap();
+        // the history must not be mistaken for the current state, so it is not retained
+        if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, false, payload) {
+            warn!("mqtt error: {e:?}")
+        }
+    }
+
+    fn poll_commands(&mut self) -> Vec<(String, InverterCommand)> {
+        let mut commands = Vec::new();
+        while let Some((topic, payload)) = self.client.try_receive() {
+            commands.extend(parse_command(&topic, &payload));
+        }
+        commands
+    }
+
+    fn publish_command_response(&mut self, response: &CommandResponse) {
+        let command = &response.command;
+        let status = response.status;
+        if status != CommandStatus::Acknowledged {
+            warn!(
+                "command {command:?} for inverter {:?} {}",
+                response.inverter,
+                status.as_str()
+            );
+        }
+        if let (
+            InverterCommand::SetPowerLimit(PowerLimit::Percent(percent)),
+            CommandStatus::Acknowledged,
+        ) = (command, status)
+        {
+            self.publish_value(&response.inverter, "power_limit", percent.to_string());
+        }
+        // responses are events rather than state, so they are not retained
+        let topic = format!(
+            "{}/{}/response",
+            base_topic(&response.inverter),
+            command.name()
+        );
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, false, status.as_str())
+        {
+            warn!("mqtt error: {e:?}")
+        }
+    }
 }
@@ -0,0 +1,141 @@
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Utc};
+use std::time::Duration;
+
+// inverters start shortly before sunrise and stop shortly after sunset
+static TWILIGHT_MARGIN: Duration = Duration::from_secs(30 * 60);
+
+/// `SunTimes` is the outcome of the sunrise equation for a single day.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SunTimes {
+    Regular {
+        sunrise: DateTime<Utc>,
+        sunset: DateTime<Utc>,
+    },
+    PolarDay,
+    PolarNight,
+}
+
+fn julian_to_utc(julian: f64) -> DateTime<Utc> {
+    let seconds = (julian - 2_440_587.5) * 86_400.;
+    Utc.timestamp_opt(seconds.round() as i64, 0).unwrap()
+}
+
+/// Computes sunrise and sunset of the given day with the sunrise equation,
+/// which is accurate to a few minutes. Latitude and longitude are in degrees,
+/// north and east being positive.
+pub fn sun_times(latitude: f64, longitude: f64, date: NaiveDate) -> SunTimes {
+    // days since noon of 2000-01-01
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()).num_days() as f64;
+    let mean_solar_time = days - longitude / 360.;
+    let mean_anomaly = (357.5291 + 0.98560028 * mean_solar_time).rem_euclid(360.);
+    let m = mean_anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2. * m).sin() + 0.0003 * (3. * m).sin();
+    let ecliptic_longitude = (mean_anomaly + center + 180. + 102.9372).rem_euclid(360.);
+    let lambda = ecliptic_longitude.to_radians();
+    let transit = 2_451_545. + mean_solar_time + 0.0053 * m.sin() - 0.0069 * (2. * lambda).sin();
+
+    let declination = (lambda.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let phi = latitude.to_radians();
+    // -0.833° accounts for refraction and the diameter of the sun
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - phi.sin() * declination.sin())
+        / (phi.cos() * declination.cos());
+    if cos_hour_angle < -1. {
+        return SunTimes::PolarDay;
+    }
+    if cos_hour_angle > 1. {
+        return SunTimes::PolarNight;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    SunTimes::Regular {
+        sunrise: julian_to_utc(transit - hour_angle / 360.),
+        sunset: julian_to_utc(transit + hour_angle / 360.),
+    }
+}
+
+/// `SunPosition` is where the sun is seen from a location on earth, in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SunPosition {
+    /// Clockwise from north, i.e. 90° is east and 180° is south
+    pub azimuth: f64,
+    /// Above the horizon, negative at night
+    pub elevation: f64,
+}
+
+/// Computes the position of the sun at the given time with the same
+/// approximation as `sun_times`. Latitude and longitude are in degrees,
+/// north and east being positive.
+pub fn sun_position(latitude: f64, longitude: f64, time: DateTime<Utc>) -> SunPosition {
+    // days since noon of 2000-01-01
+    let days = time.timestamp() as f64 / 86_400. - 10_957.5;
+    let m = (357.5291 + 0.98560028 * days).rem_euclid(360.).to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2. * m).sin() + 0.0003 * (3. * m).sin();
+    let lambda = (m.to_degrees() + center + 180. + 102.9372)
+        .rem_euclid(360.)
+        .to_radians();
+    let obliquity = 23.4397_f64.to_radians();
+    let declination = (lambda.sin() * obliquity.sin()).asin();
+    let right_ascension = (lambda.sin() * obliquity.cos()).atan2(lambda.cos());
+
+    let sidereal_time = (280.16 + 360.9856235 * days + longitude).to_radians();
+    let hour_angle = sidereal_time - right_ascension;
+    let phi = latitude.to_radians();
+    let elevation =
+        (phi.sin() * declination.sin() + phi.cos() * declination.cos() * hour_angle.cos()).asin();
+    // measured from south towards west
+    let azimuth = hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * phi.sin() - declination.tan() * phi.cos());
+    SunPosition {
+        azimuth: (azimuth.to_degrees() + 180.).rem_euclid(360.),
+        elevation: elevation.to_degrees(),
+    }
+}
+
+/// `SunSchedule` tells whether inverters at a location can be expected to produce.
+#[derive(Clone, Copy, Debug)]
+pub struct SunSchedule {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl SunSchedule {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+        }
+    }
+
+    /// The time until the next daylight period starts, zero during daylight.
+    ///
+    /// Daylight includes some twilight on both ends. During polar nights,
+    /// a day is returned, after which the question has to be asked again.
+    pub fn time_until_daylight(&self, now: DateTime<Utc>) -> Duration {
+        let margin = chrono::Duration::from_std(TWILIGHT_MARGIN).unwrap();
+        let mut next_start: Option<DateTime<Utc>> = None;
+        // the day in UTC differs from the local day, so the neighbouring days are checked, too
+        let today = now.date_naive();
+        for date in [today - Days::new(1), today, today + Days::new(1)] {
+            match sun_times(self.latitude, self.longitude, date) {
+                SunTimes::PolarDay => {
+                    if date == today {
+                        return Duration::ZERO;
+                    }
+                }
+                SunTimes::PolarNight => {}
+                SunTimes::Regular { sunrise, sunset } => {
+                    let (start, end) = (sunrise - margin, sunset + margin);
+                    if start <= now && now <= end {
+                        return Duration::ZERO;
+                    }
+                    if start > now && next_start.is_none_or(|next| start < next) {
+                        next_start = Some(start);
+                    }
+                }
+            }
+        }
+        next_start.map_or(Duration::from_secs(86_400), |start| {
+            (start - now).to_std().unwrap_or_default()
+        })
+    }
+}
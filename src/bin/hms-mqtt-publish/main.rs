@@ -2,32 +2,89 @@
 // TODO: support publishing to S-Miles cloud, too
 
 mod logging;
+mod poller;
 mod rumqttc_wrapper;
 
+use hms2mqtt::analysis::{Analysis, AnalysisConfig};
+use hms2mqtt::capture::{Recorder, Replay};
+use hms2mqtt::derived::{Derivation, PanelConfig};
+use hms2mqtt::discovery::{Discovery, Subnet};
 use hms2mqtt::home_assistant::HomeAssistant;
-use hms2mqtt::inverter::Inverter;
+use hms2mqtt::inverter::{Inverter, RealDataCommand, MIN_UPDATE_INTERVAL};
+use hms2mqtt::lifetime::LifetimeEnergy;
 use hms2mqtt::metric_collector::MetricCollector;
 use hms2mqtt::mqtt_config;
+use hms2mqtt::plausibility::{Filter, PlausibilityConfig};
 use hms2mqtt::simple_mqtt::SimpleMqtt;
+use hms2mqtt::sun::SunSchedule;
 use mqtt_config::MqttConfig;
+use poller::{poll_inverter, Event, Locator, Stages};
 use rumqttc_wrapper::RumqttcWrapper;
 use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{error, info};
+use log::{error, info, warn};
+
+#[derive(Debug, Deserialize)]
+struct InverterConfig {
+    name: String,
+    #[serde(default)]
+    host: String,
+    // serial number of the DTU, to find it on the network instead of giving its host
+    serial: Option<String>,
+    // rated power in watts, needed to apply power limits given in watts
+    rated_power: Option<f32>,
+    // command to fetch readings with, "legacy" or "new", detected if not given
+    real_data: Option<RealDataCommand>,
+    // modules connected to each port, in the order the DTU reports the ports
+    #[serde(default)]
+    panels: Vec<PanelConfig>,
+    // file to record the traffic with the DTU to
+    record: Option<String>,
+    // file with recorded traffic to replay instead of talking to the DTU
+    replay: Option<String>,
+}
 
 #[derive(Debug, Deserialize)]
 struct Config {
-    inverter_host: String,
+    inverter_host: Option<String>,
+    #[serde(default)]
+    inverters: Vec<InverterConfig>,
     update_interval: Option<u64>,
+    // location of the inverters, to poll less often at night
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    // network to search for DTUs, defaults to the /24 network of this machine
+    discovery_subnet: Option<String>,
+    // file to keep the lifetime energy counters in, next to config.toml by default
+    state_file: Option<String>,
+    // rules to check readings against before they are published
+    #[serde(default)]
+    plausibility: PlausibilityConfig,
+    // detection of strings that deliver less than expected
+    #[serde(default)]
+    underperformance: AnalysisConfig,
     home_assistant: Option<MqttConfig>,
     simple_mqtt: Option<MqttConfig>,
 }
 
 static REQUEST_DELAY_DEFAULT: u64 = 30_500;
 
+static STATE_FILE_DEFAULT: &str = "state.json";
+
+// the state file is written at most this often, to spare the SD cards of small devices
+static STATE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+// how often the collectors are checked for incoming commands
+static COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 fn main() {
     logging::init_logger();
     info!("Running revision: {}", env!("GIT_HASH"));
@@ -51,26 +108,107 @@ fn main() {
         "loading configuration from {}",
         path.to_str().expect("Cannot retrieve path")
     );
-    let contents = fs::read_to_string(path).expect("Could not read config.toml");
+    let contents = fs::read_to_string(&path).expect("Could not read config.toml");
     let config: Config = toml::from_str(&contents).expect("toml config unparsable");
 
-    if config
-        .update_interval
-        .is_some_and(|value| value > REQUEST_DELAY_DEFAULT)
+    let update_interval = config.update_interval.unwrap_or(REQUEST_DELAY_DEFAULT);
+    info!(
+        "using update interval of {:.2}s",
+        (update_interval as f64 / 1000.)
+    );
+    if update_interval < MIN_UPDATE_INTERVAL.as_millis() as u64 {
+        warn!(
+            "inverters hand out fresh readings at most every {:.2}s",
+            MIN_UPDATE_INTERVAL.as_secs_f64()
+        );
+    }
+
+    let sun = match (config.latitude, config.longitude) {
+        (Some(latitude), Some(longitude)) => {
+            info!("polling less often between dusk and dawn at {latitude}, {longitude}");
+            Some(SunSchedule::new(latitude, longitude))
+        }
+        (None, None) => None,
+        _ => {
+            error!("Both latitude and longitude are needed to follow the sun");
+            return;
+        }
+    };
+
+    // the legacy single inverter configuration is kept as an unnamed entry
+    let mut inverters = config.inverters;
+    if let Some(host) = config.inverter_host {
+        inverters.insert(
+            0,
+            InverterConfig {
+                name: String::new(),
+                host,
+                serial: None,
+                rated_power: None,
+                real_data: None,
+                panels: Vec::new(),
+                record: None,
+                replay: None,
+            },
+        );
+    }
+    // DTUs are searched for when given by serial, or to help filling in the configuration
+    let needs_discovery = inverters.is_empty() || inverters.iter().any(|i| i.serial.is_some());
+    let discovery = match config.discovery_subnet.as_deref().map(str::parse::<Subnet>) {
+        Some(Ok(subnet)) => Some(Discovery::new(subnet)),
+        Some(Err(e)) => {
+            error!("{e}");
+            return;
+        }
+        None if !needs_discovery => None,
+        None => match Subnet::local() {
+            Ok(subnet) => Some(Discovery::new(subnet)),
+            Err(e) => {
+                warn!("Could not determine the local network: {e}");
+                None
+            }
+        },
+    };
+    if inverters.is_empty() {
+        error!("No inverter configured. Set either inverter_host or add [[inverters]] entries");
+        for dtu in discovery.iter().flat_map(Discovery::scan) {
+            info!("found DTU {} at {}", dtu.dtu_sn, dtu.address.ip());
+        }
+        return;
+    }
+    if let Some(inverter) = inverters
+        .iter()
+        .find(|i| i.host.is_empty() && i.serial.is_none() && i.replay.is_none())
     {
-        info!(
-            "using non-default update interval of {:.2}s",
-            (config.update_interval.unwrap() as f64 / 1000.)
-        )
-    } else {
-        info!(
-            "using default update interval of {:.2}s",
-            (REQUEST_DELAY_DEFAULT as f64 / 1000.)
-        )
+        error!(
+            "Inverter {:?} has neither host, serial nor replay",
+            inverter.name
+        );
+        return;
+    }
+    if discovery.is_none() && inverters.iter().any(|i| i.serial.is_some()) {
+        error!("Inverters given by serial need discovery_subnet to be set");
+        return;
+    }
+    let mut names = HashSet::new();
+    if let Some(duplicate) = inverters.iter().find(|i| !names.insert(&i.name)) {
+        error!(
+            "Inverter name {:?} is configured more than once",
+            duplicate.name
+        );
+        return;
     }
 
-    info!("inverter host: {}", config.inverter_host);
-    let mut inverter = Inverter::new(&config.inverter_host);
+    let state_file = config
+        .state_file
+        .map_or_else(|| path.with_file_name(STATE_FILE_DEFAULT), PathBuf::from);
+    let mut lifetime_energy = match LifetimeEnergy::load(&state_file) {
+        Ok(lifetime_energy) => lifetime_energy,
+        Err(e) => {
+            error!("Could not read state file {}: {e}", state_file.display());
+            return;
+        }
+    };
 
     let mut output_channels: Vec<Box<dyn MetricCollector>> = Vec::new();
     if let Some(config) = config.home_assistant {
@@ -83,21 +221,135 @@ fn main() {
         output_channels.push(Box::new(SimpleMqtt::<RumqttcWrapper>::new(&config)));
     }
 
+    // every inverter is polled on its own schedule, readings are funneled to the collectors
+    let (sender, receiver) = mpsc::channel();
+    let mut command_senders = HashMap::new();
+    for inverter_config in inverters {
+        match &inverter_config.serial {
+            Some(serial) => info!("inverter {:?} with DTU {serial}", inverter_config.name),
+            None => info!(
+                "inverter {:?} at host: {}",
+                inverter_config.name, inverter_config.host
+            ),
+        }
+        let locator = inverter_config
+            .serial
+            .zip(discovery)
+            .map(|(serial, discovery)| Locator { serial, discovery });
+        let recorder = match inverter_config
+            .record
+            .as_deref()
+            .map(Recorder::create)
+            .transpose()
+        {
+            Ok(recorder) => recorder,
+            Err(e) => {
+                error!("Could not open recording: {e}");
+                return;
+            }
+        };
+        let replay = match inverter_config
+            .replay
+            .as_deref()
+            .map(Replay::open)
+            .transpose()
+        {
+            Ok(replay) => replay,
+            Err(e) => {
+                error!("Could not read replay: {e}");
+                return;
+            }
+        };
+        let inverter = Inverter::new(&inverter_config.name, &inverter_config.host)
+            .with_rated_power(inverter_config.rated_power)
+            .with_real_data_command(inverter_config.real_data)
+            .with_recorder(recorder)
+            .with_replay(replay);
+        let stages = Stages {
+            filter: Filter::new(config.plausibility.clone()),
+            derivation: Derivation::new(inverter_config.panels.clone()),
+            analysis: Analysis::new(config.underperformance.clone(), inverter_config.panels, sun),
+        };
+        let sender = sender.clone();
+        let (command_sender, commands) = mpsc::channel();
+        command_senders.insert(inverter_config.name, command_sender);
+        thread::spawn(move || {
+            poll_inverter(
+                inverter,
+                stages,
+                update_interval,
+                sun,
+                locator,
+                sender,
+                commands,
+            )
+        });
+    }
+    drop(sender);
+
+    // the counters are saved once more before the publisher stops
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_requested = shutdown.clone();
+    if let Err(e) = ctrlc::set_handler(move || shutdown_requested.store(true, Ordering::SeqCst)) {
+        warn!("Could not install the shutdown handler: {e}");
+    }
+    let save_state = |lifetime_energy: &mut LifetimeEnergy| {
+        if let Err(e) = lifetime_energy.save() {
+            warn!("Could not write state file {}: {e}", state_file.display());
+        }
+    };
+    let mut last_save = Instant::now();
+
     loop {
-        if let Some(r) = inverter.update_state() {
-            output_channels.iter_mut().for_each(|channel| {
-                channel.publish(&r);
-            })
+        if shutdown.load(Ordering::SeqCst) {
+            info!("Shutting down");
+            save_state(&mut lifetime_energy);
+            return;
+        }
+        match receiver.recv_timeout(COMMAND_POLL_INTERVAL) {
+            Ok(mut event) => {
+                // the counters are kept here, as the state file is shared by all inverters
+                if let Event::Reading(reading) = &mut event {
+                    lifetime_energy.update(reading);
+                    if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+                        save_state(&mut lifetime_energy);
+                        last_save = Instant::now();
+                    }
+                }
+                output_channels.iter_mut().for_each(|channel| match &event {
+                    Event::Reading(reading) => channel.publish(reading),
+                    Event::Config(inverter, config) => channel.publish_config(inverter, config),
+                    Event::DeviceInfo(inverter, info) => {
+                        channel.publish_device_info(inverter, info)
+                    }
+                    Event::Alarm(inverter, alarm) => channel.publish_alarm(inverter, alarm),
+                    Event::Underperformance(inverter, event) => {
+                        channel.publish_underperformance(inverter, event)
+                    }
+                    Event::History(inverter, history) => channel.publish_history(inverter, history),
+                    Event::CommandResponse(response) => channel.publish_command_response(response),
+                })
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                save_state(&mut lifetime_energy);
+                return;
+            }
         }
 
-        // TODO: the sleep has to move into the Inverter struct in an async implementation
-        if config
-            .update_interval
-            .is_some_and(|value| value > REQUEST_DELAY_DEFAULT)
+        for (inverter, command) in output_channels
+            .iter_mut()
+            .flat_map(|channel| channel.poll_commands())
         {
-            thread::sleep(Duration::from_millis(config.update_interval.unwrap()));
-        } else {
-            thread::sleep(Duration::from_millis(REQUEST_DELAY_DEFAULT));
+            match command_senders.get(&inverter) {
+                Some(command_sender) => {
+                    info!("received command {command:?} for inverter {inverter:?}");
+                    if command_sender.send(command).is_err() {
+                        warn!("inverter {inverter:?} is no longer polled");
+                    }
+                }
+                None => warn!("ignoring command for unknown inverter {inverter:?}"),
+            }
         }
     }
 }
@@ -0,0 +1,292 @@
+use chrono::Utc;
+use hms2mqtt::alarm::Alarm;
+use hms2mqtt::analysis::{Analysis, Underperformance};
+use hms2mqtt::command::{CommandResponse, CommandStatus, InverterCommand};
+use hms2mqtt::derived::Derivation;
+use hms2mqtt::device_info::DeviceInfo;
+use hms2mqtt::discovery::Discovery;
+use hms2mqtt::dtu_config::DtuConfig;
+use hms2mqtt::history::PowerHistory;
+use hms2mqtt::inverter::{Inverter, InverterError};
+use hms2mqtt::plausibility::Filter;
+use hms2mqtt::reading::Reading;
+use hms2mqtt::sun::SunSchedule;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+// upper bound for the back off while an inverter refuses connections
+static BACKOFF_DELAY_MAX: u64 = 600_000;
+
+// the DTU of an inverter given by serial is searched for again after this many failed connects
+static REDISCOVER_AFTER: u32 = 5;
+
+// at night, inverters are only checked now and then until the next sunrise is near
+static NIGHT_UPDATE_INTERVAL: Duration = Duration::from_secs(900);
+
+// the DTU configuration and device info rarely change and are only fetched once per hour
+static CONFIG_UPDATE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// alarms are checked less often than readings to keep the load on the DTU low
+static ALARM_UPDATE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Everything the polling threads hand over to the collectors
+pub enum Event {
+    Reading(Reading),
+    Config(String, DtuConfig),
+    DeviceInfo(String, DeviceInfo),
+    Alarm(String, Alarm),
+    Underperformance(String, Underperformance),
+    History(String, PowerHistory),
+    CommandResponse(CommandResponse),
+}
+
+/// `Stages` process every reading of an inverter before it is handed to the collectors.
+pub struct Stages {
+    pub filter: Filter,
+    pub derivation: Derivation,
+    pub analysis: Analysis,
+}
+
+/// `Locator` finds the DTU of an inverter that is configured by serial number.
+pub struct Locator {
+    pub serial: String,
+    pub discovery: Discovery,
+}
+
+impl Locator {
+    /// Points the inverter to the current address of its DTU, returns whether it was found.
+    fn locate(&self, inverter: &mut Inverter) -> bool {
+        match self.discovery.locate(&self.serial) {
+            Some(address) if address.to_string() != inverter.host() => {
+                inverter.set_host(&address.to_string());
+                true
+            }
+            Some(_) => {
+                debug!("{}: DTU {} did not move", inverter.name(), self.serial);
+                true
+            }
+            None => {
+                warn!("{}: DTU {} not found", inverter.name(), self.serial);
+                false
+            }
+        }
+    }
+}
+
+// the delay doubles with every failure in a row, up to BACKOFF_DELAY_MAX
+fn backoff(update_interval: u64, failures: u32) -> u64 {
+    (update_interval << (failures - 1)).min(BACKOFF_DELAY_MAX)
+}
+
+/// Fetches the rarely changing details of the DTU, returns whether all of them arrived.
+fn fetch_details(inverter: &mut Inverter, events: &mut Vec<Event>) -> bool {
+    let name = inverter.name().to_string();
+    let config = inverter.get_config();
+    let info = inverter.get_device_info();
+    let complete = config.is_ok() && info.is_ok();
+    match config {
+        Ok(config) => events.push(Event::Config(name.clone(), config)),
+        Err(e) => warn!("{name}: fetching configuration failed: {e}"),
+    }
+    match info {
+        Ok(info) => events.push(Event::DeviceInfo(name, info)),
+        Err(e) => warn!("{name}: fetching device info failed: {e}"),
+    }
+    complete
+}
+
+/// Fetches the alarms that were raised since the previous call, returns whether that succeeded.
+fn fetch_alarms(inverter: &mut Inverter, events: &mut Vec<Event>) -> bool {
+    let name = inverter.name().to_string();
+    match inverter.get_new_alarms() {
+        Ok(alarms) => {
+            for alarm in alarms {
+                info!("{name}: alarm {} ({})", alarm.code, alarm.text);
+                events.push(Event::Alarm(name.clone(), alarm));
+            }
+            true
+        }
+        Err(e) => {
+            warn!("{name}: fetching alarms failed: {e}");
+            false
+        }
+    }
+}
+
+/// Fetches the power curve of the current day, returns whether that succeeded.
+fn fetch_history(inverter: &mut Inverter, events: &mut Vec<Event>) -> bool {
+    let name = inverter.name().to_string();
+    match inverter.get_power_history() {
+        Ok(history) => {
+            info!(
+                "{name}: fetched {} samples of the power history",
+                history.samples.len()
+            );
+            events.push(Event::History(name, history));
+            true
+        }
+        Err(e) => {
+            warn!("{name}: fetching power history failed: {e}");
+            false
+        }
+    }
+}
+
+/// Waits for the given delay while executing the commands that arrive in the meantime.
+fn wait_for_commands(
+    inverter: &mut Inverter,
+    commands: &Receiver<InverterCommand>,
+    delay: Duration,
+    sender: &Sender<Event>,
+) {
+    let deadline = Instant::now() + delay;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let command = match commands.recv_timeout(timeout) {
+            Ok(command) => command,
+            Err(RecvTimeoutError::Timeout) => return,
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                return;
+            }
+        };
+
+        let result = inverter.execute(&command);
+        let status = CommandStatus::from(&result);
+        match &result {
+            Ok(command) => info!("{}: executed {command:?}", inverter.name()),
+            Err(e) => warn!("{}: {command:?} failed: {e}", inverter.name()),
+        }
+        let response = CommandResponse {
+            inverter: inverter.name().to_string(),
+            command: result.unwrap_or(command),
+            status,
+        };
+        // a vanished receiver is noticed by the polling loop
+        let _ = sender.send(Event::CommandResponse(response));
+    }
+}
+
+pub fn poll_inverter(
+    mut inverter: Inverter,
+    mut stages: Stages,
+    update_interval: u64,
+    sun: Option<SunSchedule>,
+    locator: Option<Locator>,
+    sender: Sender<Event>,
+    commands: Receiver<InverterCommand>,
+) {
+    let mut failed_connects = 0;
+    let mut retried = false;
+    let mut next_config_update = Instant::now();
+    let mut next_alarm_update = Instant::now();
+    // readings are missing since the start and after every outage, until the history is fetched
+    let mut backfill_due = true;
+    // the address of an inverter given by serial is unknown at first
+    let mut failures_since_discovery = REDISCOVER_AFTER;
+    let mut failed_scans = 0;
+    let mut next_discovery = Instant::now();
+    loop {
+        if let Some(locator) = &locator {
+            if failures_since_discovery >= REDISCOVER_AFTER && Instant::now() >= next_discovery {
+                failures_since_discovery = 0;
+                if locator.locate(&mut inverter) {
+                    failed_scans = 0;
+                } else {
+                    // scanning the subnet is costly, scans that come up empty back off like connects
+                    failed_scans = (failed_scans + 1).min(16);
+                    next_discovery = Instant::now()
+                        + Duration::from_millis(backoff(update_interval, failed_scans));
+                }
+            }
+        }
+
+        let delay = match inverter.update_state() {
+            Ok(mut reading) => {
+                failed_connects = 0;
+                failures_since_discovery = 0;
+                retried = false;
+                if reading.stale {
+                    debug!(
+                        "{}: inverter repeated its previous reading",
+                        inverter.name()
+                    );
+                }
+                // implausible readings are dropped, but the other details are fetched nonetheless
+                let mut events = Vec::new();
+                if stages.filter.apply(&mut reading) {
+                    stages.derivation.apply(&mut reading);
+                    let name = inverter.name();
+                    for event in stages.analysis.apply(&mut reading) {
+                        warn!(
+                            "{name}: port {} of inverter {} is underperforming",
+                            event.port, event.inverter_serial
+                        );
+                        events.push(Event::Underperformance(name.to_string(), event));
+                    }
+                    events.push(Event::Reading(reading));
+                }
+                if Instant::now() >= next_config_update && fetch_details(&mut inverter, &mut events)
+                {
+                    next_config_update = Instant::now() + CONFIG_UPDATE_INTERVAL;
+                }
+                if Instant::now() >= next_alarm_update && fetch_alarms(&mut inverter, &mut events) {
+                    next_alarm_update = Instant::now() + ALARM_UPDATE_INTERVAL;
+                }
+                if backfill_due && fetch_history(&mut inverter, &mut events) {
+                    backfill_due = false;
+                }
+                if events.into_iter().any(|event| sender.send(event).is_err()) {
+                    // the receiving end is gone, i.e. the publisher is shutting down
+                    return;
+                }
+                // the inverter does not hand out fresh readings any sooner
+                update_interval.max(inverter.time_until_update().as_millis() as u64)
+            }
+            Err(e @ (InverterError::Crc { .. } | InverterError::Frame(_))) if !retried => {
+                // a corrupted transmission is worth another try right away
+                warn!("{}: {e}, retrying", inverter.name());
+                retried = true;
+                0
+            }
+            Err(
+                e @ (InverterError::Resolve(_) | InverterError::Connect(_) | InverterError::Io(_)),
+            ) => {
+                debug!("{}: {e}", inverter.name());
+                retried = false;
+                backfill_due = true;
+                // a DTU that times out or hangs up is still at its address, it may have moved
+                // only if the address cannot be reached at all
+                if matches!(e, InverterError::Resolve(_) | InverterError::Connect(_)) {
+                    failures_since_discovery += 1;
+                }
+                failed_connects = (failed_connects + 1).min(16);
+                backoff(update_interval, failed_connects)
+            }
+            Err(e) => {
+                warn!("{}: {e}", inverter.name());
+                retried = false;
+                update_interval
+            }
+        };
+
+        let delay = match sun.map(|sun| sun.time_until_daylight(Utc::now())) {
+            Some(until_daylight) if !until_daylight.is_zero() => {
+                // the inverter is asleep, but the DTU may still take commands in the meantime
+                delay.max(until_daylight.min(NIGHT_UPDATE_INTERVAL).as_millis() as u64)
+            }
+            _ => delay,
+        };
+
+        // TODO: the sleep has to move into the Inverter struct in an async implementation
+        wait_for_commands(
+            &mut inverter,
+            &commands,
+            Duration::from_millis(delay),
+            &sender,
+        );
+    }
+}
@@ -1,4 +1,11 @@
-use std::{thread, time::Duration};
+use std::{
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use hms2mqtt::{
     mqtt_config::MqttConfig,
@@ -7,13 +14,16 @@ use hms2mqtt::{
 use log::warn;
 use rumqttc::{
     tokio_rustls::{self, rustls::ClientConfig},
-    Client, MqttOptions,
+    Client, Event, MqttOptions, Packet,
     QoS::AtMostOnce,
     Transport,
 };
 
 pub struct RumqttcWrapper {
     client: Client,
+    // subscriptions are renewed whenever the connection to the broker is re-established
+    subscriptions: Arc<Mutex<Vec<(String, rumqttc::QoS)>>>,
+    messages: Receiver<(String, Vec<u8>)>,
 }
 
 fn match_qos(qos: mqtt_wrapper::QoS) -> rumqttc::QoS {
@@ -26,9 +36,17 @@ fn match_qos(qos: mqtt_wrapper::QoS) -> rumqttc::QoS {
 
 impl mqtt_wrapper::MqttWrapper for RumqttcWrapper {
     fn subscribe(&mut self, topic: &str, qos: mqtt_wrapper::QoS) -> anyhow::Result<()> {
+        self.subscriptions
+            .lock()
+            .expect("subscriptions are never poisoned")
+            .push((topic.to_string(), match_qos(qos)));
         Ok(self.client.subscribe(topic, match_qos(qos))?)
     }
 
+    fn try_receive(&mut self) -> Option<(String, Vec<u8>)> {
+        self.messages.try_recv().ok()
+    }
+
     fn publish<S, V>(
         &mut self,
         topic: S,
@@ -104,17 +122,44 @@ impl mqtt_wrapper::MqttWrapper for RumqttcWrapper {
         }
 
         let (client, mut connection) = Client::new(mqttoptions, 512);
+        let subscriptions: Arc<Mutex<Vec<(String, rumqttc::QoS)>>> = Arc::default();
+        let (sender, messages) = mpsc::channel();
 
+        let resubscriber = client.clone();
+        let renewed_subscriptions = subscriptions.clone();
         thread::spawn(move || {
             // keep polling the event loop to make sure outgoing messages get sent
             // the call to .iter() blocks and suspends the thread effectively by
             // calling .recv() under the hood. This implies that the loop terminates
             // once the client unsubs
-            for _ in connection.iter() {}
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        // the receiver is gone if the wrapper was dropped, nothing to do then
+                        let _ = sender.send((publish.topic, publish.payload.to_vec()));
+                    }
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        for (topic, qos) in renewed_subscriptions
+                            .lock()
+                            .expect("subscriptions are never poisoned")
+                            .iter()
+                        {
+                            if let Err(e) = resubscriber.try_subscribe(topic, *qos) {
+                                warn!("renewing subscription to {topic} failed: {e}");
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
         });
         if let Err(e) = client.subscribe("hms800wt2", AtMostOnce) {
             warn!("subscription to base topic failed: {e}");
         }
-        Self { client }
+        Self {
+            client,
+            subscriptions,
+            messages,
+        }
     }
 }
@@ -0,0 +1,38 @@
+// A stand-in for a DTU, so that the publisher can be run end to end without hardware.
+//
+// usage: mock-dtu [<listen address>] [<script.toml>]
+
+use env_logger::{Builder, Env};
+use hms2mqtt::mock_dtu::{MockConfig, MockDtu};
+use std::fs;
+
+use log::error;
+
+static LISTEN_ADDRESS_DEFAULT: &str = "0.0.0.0:10081";
+
+fn main() {
+    Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let mut args = std::env::args().skip(1);
+    let address = args
+        .next()
+        .unwrap_or_else(|| LISTEN_ADDRESS_DEFAULT.to_string());
+    let config = match args.next() {
+        Some(path) => {
+            let contents = fs::read_to_string(&path).expect("Could not read script");
+            toml::from_str::<MockConfig>(&contents).expect("toml script unparsable")
+        }
+        None => MockConfig::default(),
+    };
+
+    let dtu = match MockDtu::bind(&address, config) {
+        Ok(dtu) => dtu,
+        Err(e) => {
+            error!("Could not listen on {address}: {e}");
+            return;
+        }
+    };
+    if let Err(e) = dtu.serve() {
+        error!("{e}");
+    }
+}
@@ -0,0 +1,39 @@
+use hms2mqtt::discovery::{Discovery, Subnet};
+use hms2mqtt::mock_dtu::{MockConfig, MockDtu};
+use std::net::Ipv4Addr;
+use std::thread;
+
+#[test]
+fn subnet_hosts() {
+    let subnet: Subnet = "192.168.1.77/24".parse().expect("valid subnet");
+    assert_eq!(subnet.to_string(), "192.168.1.0/24");
+    let hosts: Vec<Ipv4Addr> = subnet.hosts().collect();
+    assert_eq!(hosts.len(), 254);
+    assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+    assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+    let single: Subnet = "10.0.0.5".parse().expect("valid address");
+    assert_eq!(
+        single.hosts().collect::<Vec<_>>(),
+        [Ipv4Addr::new(10, 0, 0, 5)]
+    );
+
+    assert!("10.0.0.0/8".parse::<Subnet>().is_err());
+    assert!("10.0.0/24".parse::<Subnet>().is_err());
+}
+
+#[test]
+fn mock_dtu_is_discovered() {
+    let config = MockConfig::default();
+    let dtu_sn = config.dtu_sn.clone();
+    let dtu = MockDtu::bind("127.0.0.1:0", config).expect("bind mock DTU");
+    let address = dtu.local_addr().unwrap();
+    thread::spawn(move || dtu.serve());
+
+    let discovery = Discovery::new("127.0.0.1/32".parse().unwrap()).with_port(address.port());
+    let found = discovery.scan();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].dtu_sn, dtu_sn);
+    assert_eq!(discovery.locate(&dtu_sn), Some(address));
+    assert_eq!(discovery.locate("4143A0999999"), None);
+}
@@ -1,5 +1,33 @@
+use std::cell::RefCell;
+
 use hms2mqtt::{mqtt_config::MqttConfig, mqtt_wrapper::MqttWrapper};
 
+thread_local! {
+    // everything the testers of the current test published, as collectors
+    // keep their client to themselves
+    static PUBLISHED: RefCell<Vec<(String, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns the messages published since the last call, keyed by topic.
+fn published() -> std::collections::HashMap<String, String> {
+    PUBLISHED.with_borrow_mut(|published| {
+        published
+            .drain(..)
+            .map(|(topic, payload)| (topic, String::from_utf8(payload).unwrap()))
+            .collect()
+    })
+}
+
+fn mqtt_config() -> MqttConfig {
+    MqttConfig {
+        host: "frob".to_owned(),
+        port: Some(1234),
+        username: None,
+        password: None,
+        tls: None,
+    }
+}
+
 struct MqttTester {
     published_values: Vec<(String, Vec<u8>)>,
 }
@@ -31,10 +59,16 @@ impl MqttWrapper for MqttTester {
         S: Into<String>,
         V: Into<Vec<u8>>,
     {
-        self.published_values.push((topic.into(), payload.into()));
+        let message = (topic.into(), payload.into());
+        PUBLISHED.with_borrow_mut(|published| published.push(message.clone()));
+        self.published_values.push(message);
         Ok(())
     }
 
+    fn try_receive(&mut self) -> Option<(String, Vec<u8>)> {
+        None
+    }
+
     fn new(_config: &hms2mqtt::mqtt_config::MqttConfig, _suffix: &str) -> Self {
         Self {
             published_values: Vec::new(),
@@ -44,16 +78,7 @@ impl MqttWrapper for MqttTester {
 
 #[test]
 fn publish_one_message() {
-    let mut mqtt = MqttTester::new(
-        &MqttConfig {
-            host: "frob".to_owned(),
-            port: Some(1234),
-            username: None,
-            password: None,
-            tls: None,
-        },
-        "-test",
-    );
+    let mut mqtt = MqttTester::new(&mqtt_config(), "-test");
     let result = mqtt.publish(
         "foo",
         hms2mqtt::mqtt_wrapper::QoS::AtMostOnce,
@@ -64,3 +89,385 @@ fn publish_one_message() {
     assert!(!mqtt.is_empty());
     assert_eq!(mqtt.len(), 1);
 }
+
+#[test]
+fn parse_power_limit() {
+    use hms2mqtt::command::PowerLimit;
+
+    assert_eq!("50".parse::<PowerLimit>(), Ok(PowerLimit::Percent(50.)));
+    assert_eq!(
+        "70.5 %".parse::<PowerLimit>(),
+        Ok(PowerLimit::Percent(70.5))
+    );
+    assert_eq!("400W".parse::<PowerLimit>(), Ok(PowerLimit::Watts(400.)));
+    assert!("full".parse::<PowerLimit>().is_err());
+}
+
+#[test]
+fn parse_inverter_command() {
+    use hms2mqtt::command::{InverterCommand, PowerLimit};
+
+    assert_eq!(
+        InverterCommand::parse("power_limit", "400W"),
+        Ok(InverterCommand::SetPowerLimit(PowerLimit::Watts(400.)))
+    );
+    assert_eq!(
+        InverterCommand::parse("turn_off", ""),
+        Ok(InverterCommand::TurnOff)
+    );
+    assert_eq!(
+        InverterCommand::parse("restart_dtu", "PRESS"),
+        Ok(InverterCommand::RestartDtu)
+    );
+    assert!(InverterCommand::parse("power_limit", "full").is_err());
+    assert!(InverterCommand::parse("self_destruct", "").is_err());
+
+    for command in [InverterCommand::TurnOn, InverterCommand::Restart] {
+        assert_eq!(InverterCommand::parse(command.name(), ""), Ok(command));
+    }
+}
+
+#[test]
+fn alarm_texts() {
+    use hms2mqtt::alarm::alarm_text;
+
+    assert_eq!(alarm_text(141), "Grid overvoltage");
+    assert_eq!(alarm_text(149), "Island detected");
+    assert_eq!(alarm_text(4711), "Unknown alarm code 4711");
+}
+
+#[test]
+fn publish_captured_traffic() {
+    use hms2mqtt::capture::Replay;
+    use hms2mqtt::derived::Derivation;
+    use hms2mqtt::home_assistant::HomeAssistant;
+    use hms2mqtt::inverter::{Inverter, InverterError};
+    use hms2mqtt::metric_collector::MetricCollector;
+    use hms2mqtt::simple_mqtt::SimpleMqtt;
+    use std::time::Duration;
+
+    // recorded from the mock DTU, whose second reading was garbled on the way
+    let replay = Replay::open("tests/captures/mock-dtu.jsonl").unwrap();
+    let mut inverter = Inverter::new("", "")
+        .with_min_update_interval(Duration::ZERO)
+        .with_replay(Some(replay));
+    let mut reading = inverter.update_state().expect("recorded reading");
+    assert!(matches!(
+        inverter.update_state(),
+        Err(InverterError::Crc { .. })
+    ));
+    Derivation::default().apply(&mut reading);
+
+    let mut home_assistant = HomeAssistant::<MqttTester>::new(&mqtt_config());
+    home_assistant.publish(&reading);
+    let messages = published();
+    let state: serde_json::Value =
+        serde_json::from_str(&messages["solar/hms_4143A000/state"]).unwrap();
+    assert_eq!(state["dtu_sn"], "4143A0000000");
+    assert_eq!(state["pv_current_power"], "483.40");
+    assert_eq!(state["pv_daily_yield"], 3640.);
+    let config: serde_json::Value = serde_json::from_str(
+        &messages["homeassistant/sensor/hms_4143A000/hms_4143A000_pv_current_power/config"],
+    )
+    .unwrap();
+    assert_eq!(config["state_topic"], "solar/hms_4143A000/state");
+    assert_eq!(
+        config["value_template"],
+        "{{ value_json.pv_current_power }}"
+    );
+
+    let mut simple_mqtt = SimpleMqtt::<MqttTester>::new(&mqtt_config());
+    simple_mqtt.publish(&reading);
+    let messages = published();
+    assert_eq!(messages["hms800wt2/pv_current_power"], "483.4");
+    assert_eq!(messages["hms800wt2/pv_daily_yield"], "3640");
+    assert_eq!(messages["hms800wt2/pv_grid_voltage"], "231.4");
+    assert_eq!(messages["hms800wt2/pv_port1_energy"], "1204000");
+    assert_eq!(messages["hms800wt2/pv_port2_curr"], "7.31");
+}
+
+// a DTU with two ports on each of the inverters with the given serial numbers
+fn dtu_state(serials: &[i64]) -> hms2mqtt::reading::DtuState {
+    use hms2mqtt::reading::{DtuState, InverterState, PortState};
+
+    let inverters = serials
+        .iter()
+        .zip(1..)
+        .map(|(&serial, number)| InverterState {
+            serial,
+            number,
+            grid_voltage: 230.1,
+            grid_frequency: 50.,
+            power: 280.,
+            power_limit: Some(100.),
+            temperature: 30.5,
+            ..Default::default()
+        })
+        .collect();
+    let ports = serials
+        .iter()
+        .flat_map(|&inverter_serial| {
+            (1..=2).map(move |port| PortState {
+                inverter_serial,
+                port,
+                voltage: 32.,
+                current: 4.7,
+                power: 150.,
+                energy_total: 1000.,
+                daily_yield: 100.,
+                ..Default::default()
+            })
+        })
+        .collect();
+    DtuState {
+        dtu_sn: "4143A0123456".to_string(),
+        time: chrono::Utc::now(),
+        power: 280. * serials.len() as f32,
+        daily_yield: 200. * serials.len() as f64,
+        inverters,
+        ports,
+        ..Default::default()
+    }
+}
+
+fn reading(state: hms2mqtt::reading::DtuState) -> hms2mqtt::reading::Reading {
+    let mut reading = hms2mqtt::reading::Reading::new("", state);
+    hms2mqtt::derived::Derivation::default().apply(&mut reading);
+    reading
+}
+
+/// Publishes the reading with both collectors and returns what they published.
+fn publish(reading: &hms2mqtt::reading::Reading) -> std::collections::HashMap<String, String> {
+    use hms2mqtt::home_assistant::HomeAssistant;
+    use hms2mqtt::metric_collector::MetricCollector;
+    use hms2mqtt::simple_mqtt::SimpleMqtt;
+
+    published();
+    HomeAssistant::<MqttTester>::new(&mqtt_config()).publish(reading);
+    SimpleMqtt::<MqttTester>::new(&mqtt_config()).publish(reading);
+    published()
+}
+
+// asserts that Home Assistant is told about the sensor and finds its value in the state
+fn assert_sensor(messages: &std::collections::HashMap<String, String>, key: &str) {
+    let config: serde_json::Value = serde_json::from_str(
+        messages
+            .get(&format!(
+                "homeassistant/sensor/hms_4143A012/hms_4143A012_{key}/config"
+            ))
+            .unwrap_or_else(|| panic!("no config for {key}")),
+    )
+    .unwrap();
+    assert_eq!(config["unique_id"], format!("hms_4143A012_{key}"));
+    assert_eq!(
+        config["value_template"],
+        format!("{{{{ value_json.{key} }}}}")
+    );
+    let state: serde_json::Value =
+        serde_json::from_str(&messages["solar/hms_4143A012/state"]).unwrap();
+    assert!(!state[key].is_null(), "no state for {key}");
+}
+
+#[test]
+fn publish_one_inverter() {
+    let messages = publish(&reading(dtu_state(&[0x1144_0000_0001])));
+
+    for topic in [
+        "hms800wt2/pv_current_power",
+        "hms800wt2/pv_daily_yield",
+        "hms800wt2/pv_grid_voltage",
+        "hms800wt2/pv_inv_temperature",
+        "hms800wt2/pv_port1_power",
+        "hms800wt2/pv_port2_energy",
+    ] {
+        assert!(messages.contains_key(topic), "{topic} not published");
+    }
+    // a single inverter is not told apart by its serial number
+    assert!(!messages.keys().any(|topic| topic.contains("114400000001")));
+
+    for key in [
+        "pv_current_power",
+        "inv_1_grid_voltage",
+        "inv_1_temperature",
+        "pv_1_power",
+        "pv_2_energy_total",
+    ] {
+        assert_sensor(&messages, key);
+    }
+}
+
+#[test]
+fn publish_two_inverters() {
+    let messages = publish(&reading(dtu_state(&[0x1144_0000_0001, 0x1144_0000_0002])));
+
+    for topic in [
+        "hms800wt2/pv_current_power",
+        "hms800wt2/pv_daily_yield",
+        "hms800wt2/114400000001/pv_grid_voltage",
+        "hms800wt2/114400000002/pv_inv_temperature",
+        "hms800wt2/114400000001/pv_port1_power",
+        "hms800wt2/114400000002/pv_port2_energy",
+    ] {
+        assert!(messages.contains_key(topic), "{topic} not published");
+    }
+    // the ports of both inverters are numbered from 1 and only apart below their inverter
+    assert!(!messages.contains_key("hms800wt2/pv_port1_power"));
+    assert!(!messages.contains_key("hms800wt2/pv_grid_voltage"));
+
+    for key in [
+        "pv_current_power",
+        "inv_114400000001_grid_voltage",
+        "inv_114400000002_temperature",
+        "inv_114400000001_pv_1_power",
+        "inv_114400000002_pv_2_energy_total",
+    ] {
+        assert_sensor(&messages, key);
+    }
+    assert!(!messages
+        .keys()
+        .any(|topic| topic.ends_with("_inv_1_grid_voltage/config")));
+
+    // the limit of each inverter and the DC share of each port are percentages
+    for key in [
+        "inv_114400000001_power_limit",
+        "inv_114400000002_pv_1_dc_share",
+    ] {
+        assert_sensor(&messages, key);
+        let config: serde_json::Value = serde_json::from_str(
+            &messages[&format!("homeassistant/sensor/hms_4143A012/hms_4143A012_{key}/config")],
+        )
+        .unwrap();
+        assert_eq!(config["unit_of_measurement"], "%");
+        assert_eq!(config["state_class"], "measurement");
+        assert!(config["device_class"].is_null());
+    }
+}
+
+#[test]
+fn publish_three_phases() {
+    use hms2mqtt::reading::PhaseState;
+
+    let mut state = dtu_state(&[0x1144_0000_0001]);
+    state.inverters[0].phases = [229.5, 230.1, 231.]
+        .into_iter()
+        .map(|voltage| PhaseState {
+            voltage,
+            current: 0.4,
+            power: voltage * 0.4,
+            line_voltage: 398.6,
+        })
+        .collect();
+    let messages = publish(&reading(state));
+
+    assert_eq!(messages["hms800wt2/pv_grid_phase_a_voltage"], "229.5");
+    assert_eq!(messages["hms800wt2/pv_grid_phase_b_curr"], "0.4");
+    assert_eq!(messages["hms800wt2/pv_grid_phase_c_power"], "92.4");
+    assert_eq!(messages["hms800wt2/pv_grid_line_ca_voltage"], "398.6");
+    // the mean of the phases is kept for dashboards built for single-phase inverters
+    assert_eq!(messages["hms800wt2/pv_grid_voltage"], "230.1");
+
+    for key in [
+        "inv_1_phase_a_voltage",
+        "inv_1_phase_b_current",
+        "inv_1_phase_c_power",
+        "inv_1_line_ab_voltage",
+        "inv_1_line_bc_voltage",
+        "inv_1_line_ca_voltage",
+    ] {
+        assert_sensor(&messages, key);
+    }
+}
+
+#[test]
+fn publish_single_phase() {
+    let messages = publish(&reading(dtu_state(&[0x1144_0000_0001])));
+
+    assert_eq!(messages["hms800wt2/pv_grid_voltage"], "230.1");
+    assert_sensor(&messages, "inv_1_grid_voltage");
+    assert!(!messages
+        .keys()
+        .any(|topic| topic.contains("_phase_") || topic.contains("_line_")));
+}
+
+#[test]
+fn publish_status_flags() {
+    use hms2mqtt::reading::InverterStatus;
+
+    let mut state = dtu_state(&[0x1144_0000_0001]);
+    state.inverters[0].status = InverterStatus {
+        producing: true,
+        link: Some(true),
+        limited: Some(false),
+        alarm_active: Some(true),
+    };
+    let messages = publish(&reading(state));
+
+    assert_eq!(messages["hms800wt2/pv_inv_producing"], "true");
+    assert_eq!(messages["hms800wt2/pv_inv_link"], "true");
+    assert_eq!(messages["hms800wt2/pv_inv_limited"], "false");
+    assert_eq!(messages["hms800wt2/pv_inv_alarm_active"], "true");
+    let state: serde_json::Value =
+        serde_json::from_str(&messages["solar/hms_4143A012/state"]).unwrap();
+    for (key, device_class) in [
+        ("inv_1_producing", "running"),
+        ("inv_1_link", "connectivity"),
+        ("inv_1_limited", ""),
+        ("inv_1_alarm_active", "problem"),
+    ] {
+        let config: serde_json::Value = serde_json::from_str(
+            messages
+                .get(&format!(
+                    "homeassistant/binary_sensor/hms_4143A012/hms_4143A012_{key}/config"
+                ))
+                .unwrap_or_else(|| panic!("no config for {key}")),
+        )
+        .unwrap();
+        assert_eq!(
+            config["device_class"].as_str().unwrap_or_default(),
+            device_class
+        );
+        assert!(!state[key].is_null(), "no state for {key}");
+    }
+}
+
+#[test]
+fn publish_legacy_status_flags() {
+    use hms2mqtt::reading::InverterStatus;
+
+    // the legacy command reports neither the link nor the warnings of an inverter
+    let mut state = dtu_state(&[0x1144_0000_0001]);
+    state.inverters[0].status = InverterStatus {
+        producing: true,
+        limited: Some(false),
+        ..Default::default()
+    };
+    let messages = publish(&reading(state));
+
+    assert!(messages.contains_key("hms800wt2/pv_inv_producing"));
+    assert!(messages.contains_key("hms800wt2/pv_inv_limited"));
+    assert!(!messages.contains_key("hms800wt2/pv_inv_link"));
+    assert!(!messages.contains_key("hms800wt2/pv_inv_alarm_active"));
+    assert!(!messages
+        .keys()
+        .any(|topic| topic.ends_with("_inv_1_link/config")
+            || topic.ends_with("_inv_1_alarm_active/config")));
+}
+
+#[test]
+fn commands_address_the_dtu() {
+    // buttons and command topics apply to all inverters of a DTU, like the power limit
+    let messages = publish(&reading(dtu_state(&[0x1144_0000_0001, 0x1144_0000_0002])));
+
+    let buttons: Vec<_> = messages
+        .iter()
+        .filter(|(topic, _)| topic.starts_with("homeassistant/button/hms_4143A012/"))
+        .map(|(_, config)| serde_json::from_str::<serde_json::Value>(config).unwrap())
+        .collect();
+    assert_eq!(buttons.len(), 4);
+    let turn_off = buttons
+        .iter()
+        .find(|config| config["unique_id"] == "hms_4143A012_turn_off")
+        .expect("turn off button");
+    assert_eq!(turn_off["name"], "Turn Off Inverters");
+    assert_eq!(turn_off["command_topic"], "solar/hms_4143A012/turn_off/set");
+}
@@ -0,0 +1,336 @@
+use hms2mqtt::capture::{Recorder, Replay};
+use hms2mqtt::command::{InverterCommand, PowerLimit};
+use hms2mqtt::inverter::{Inverter, InverterError, RealDataCommand};
+use hms2mqtt::mock_dtu::{MockAlarm, MockConfig, MockDtu, MockPort, MockState, Step};
+use std::thread;
+use std::time::Duration;
+
+fn reading(voltage: f32) -> Step {
+    Step::Reading(MockState {
+        ports: vec![MockPort {
+            voltage,
+            current: 5.,
+            energy_total: 0,
+            daily_yield: 0,
+        }],
+        ..Default::default()
+    })
+}
+
+// starts a mock DTU on a free port and returns an inverter talking to it
+fn start_mock(config: MockConfig) -> Inverter {
+    let dtu = MockDtu::bind("127.0.0.1:0", config).expect("bind mock DTU");
+    let address = dtu.local_addr().unwrap();
+    thread::spawn(move || dtu.serve());
+    Inverter::new("mock", &address.to_string()).with_min_update_interval(Duration::ZERO)
+}
+
+#[test]
+fn scripted_faults_are_reported() {
+    let mut inverter = start_mock(MockConfig {
+        cache_duration: 0,
+        steps: vec![
+            reading(30.),
+            Step::DropConnection,
+            Step::TruncateFrame,
+            reading(40.),
+        ],
+        ..Default::default()
+    });
+
+    let reading = inverter.update_state().expect("first reading");
+    assert!(!reading.stale);
+    assert_eq!(reading.state.ports[0].voltage, 30.);
+    assert_eq!(reading.state.ports[0].power, 150.);
+    assert!(matches!(inverter.update_state(), Err(InverterError::Io(_))));
+    assert!(matches!(inverter.update_state(), Err(InverterError::Io(_))));
+    let reading = inverter.update_state().expect("reading after the faults");
+    assert_eq!(reading.state.ports[0].voltage, 40.);
+}
+
+#[test]
+fn real_data_command_is_detected() {
+    let mut legacy = start_mock(MockConfig::default());
+    let reading = legacy.update_state().expect("legacy reading");
+    assert_eq!(reading.state.inverters[0].power_limit, Some(100.));
+    assert_eq!(reading.state.inverters[0].reactive_power, None);
+    let status = reading.state.inverters[0].status;
+    assert!(status.producing);
+    assert_eq!(status.limited, Some(false));
+    assert_eq!(status.link, None);
+    assert_eq!(
+        reading.state.unknown_fields()["inverters"][0]["fields"]["bit_field"],
+        0
+    );
+
+    let config = MockConfig {
+        real_data_new: true,
+        ..Default::default()
+    };
+    let mut new = start_mock(config.clone());
+    let reading = new.update_state().expect("new reading");
+    let inverter = &reading.state.inverters[0];
+    assert_eq!(inverter.number, 1);
+    assert_eq!(inverter.power_factor, Some(1.));
+    assert_eq!(inverter.status.link, Some(true));
+    assert_eq!(inverter.status.alarm_active, Some(false));
+    assert_eq!(reading.state.ports.len(), 2);
+    assert_eq!(reading.state.ports[0].voltage, 35.);
+
+    // a garbled first reply does not lock the inverter to the legacy command
+    let mut flaky = start_mock(MockConfig {
+        steps: vec![Step::CorruptFrame, Step::Reading(MockState::default())],
+        ..config.clone()
+    });
+    assert!(matches!(
+        flaky.update_state(),
+        Err(InverterError::Crc { .. })
+    ));
+    let reading = flaky.update_state().expect("reading after the garbled one");
+    assert_eq!(reading.state.inverters[0].power_factor, Some(1.));
+
+    // neither does an empty one, which is served by the legacy command in the meantime
+    let mut asleep = start_mock(MockConfig {
+        cache_duration: 0,
+        steps: vec![
+            Step::EmptyReading,
+            Step::Reading(MockState::default()),
+            Step::Reading(MockState::default()),
+        ],
+        ..config.clone()
+    });
+    let reading = asleep.update_state().expect("legacy reading");
+    assert_eq!(reading.state.inverters[0].power_factor, None);
+    let reading = asleep.update_state().expect("new reading");
+    assert_eq!(reading.state.inverters[0].power_factor, Some(1.));
+
+    let mut configured = start_mock(config).with_real_data_command(Some(RealDataCommand::Legacy));
+    let reading = configured.update_state().expect("configured reading");
+    assert_eq!(reading.state.inverters[0].power_factor, None);
+}
+
+#[test]
+fn three_phase_reading() {
+    let port = MockPort {
+        voltage: 40.,
+        current: 10.,
+        energy_total: 0,
+        daily_yield: 0,
+    };
+    let mut inverter = start_mock(MockConfig {
+        real_data_new: true,
+        three_phase: true,
+        steps: vec![Step::Reading(MockState {
+            ports: vec![port; 6],
+            ..Default::default()
+        })],
+        ..Default::default()
+    });
+
+    let reading = inverter.update_state().expect("three-phase reading");
+    assert_eq!(reading.state.ports.len(), 6);
+    assert_eq!(reading.state.ports[5].port, 6);
+    let inverter = &reading.state.inverters[0];
+    assert_eq!(inverter.grid_voltage, 230.);
+    assert_eq!(inverter.power_limit, None);
+    assert_eq!(inverter.phases.len(), 3);
+    for phase in &inverter.phases {
+        assert_eq!(phase.voltage, 230.);
+        assert_eq!(phase.line_voltage, 398.4);
+        // 96% of 2400 W DC split across three phases
+        assert!((phase.power - 768.).abs() <= 1., "{}", phase.power);
+    }
+}
+
+#[test]
+fn packages_are_merged() {
+    let ports = (1..=5).map(|i| MockPort {
+        voltage: 30. + i as f32,
+        current: 5.,
+        energy_total: 0,
+        daily_yield: 0,
+    });
+    let mut inverter = start_mock(MockConfig {
+        cache_duration: 0,
+        ports_per_package: Some(2),
+        steps: vec![
+            Step::Reading(MockState {
+                ports: ports.collect(),
+                ..Default::default()
+            }),
+            Step::MissingPackage,
+        ],
+        ..Default::default()
+    });
+
+    let reading = inverter.update_state().expect("merged reading");
+    let voltages: Vec<f32> = reading
+        .state
+        .ports
+        .iter()
+        .map(|port| port.voltage)
+        .collect();
+    assert_eq!(voltages, [31., 32., 33., 34., 35.]);
+    assert_eq!(reading.state.inverters.len(), 1);
+    assert!(matches!(
+        inverter.update_state(),
+        Err(InverterError::Incomplete {
+            received: 2,
+            expected: 3
+        })
+    ));
+}
+
+#[test]
+fn cached_reading_is_stale() {
+    let mut inverter = start_mock(MockConfig::default());
+
+    let fresh = inverter.update_state().expect("fresh reading");
+    assert!(!fresh.stale);
+    let cached = inverter.update_state().expect("cached reading");
+    assert!(cached.stale);
+    assert_eq!(cached.state, fresh.state);
+}
+
+#[test]
+fn early_request_restarts_the_cache() {
+    let mut inverter = start_mock(MockConfig {
+        cache_duration: 1,
+        ..Default::default()
+    });
+
+    let fresh = inverter.update_state().expect("fresh reading");
+    thread::sleep(Duration::from_millis(600));
+    assert!(inverter.update_state().expect("cached reading").stale);
+    // the cache would have expired, had the early request not restarted it
+    thread::sleep(Duration::from_millis(600));
+    let cached = inverter.update_state().expect("cached reading");
+    assert!(cached.stale);
+    assert_eq!(cached.state, fresh.state);
+}
+
+#[test]
+fn min_update_interval_is_enforced() {
+    // without a cache, every request to the mock DTU yields a fresh reading
+    let mut inverter = start_mock(MockConfig {
+        cache_duration: 0,
+        ..Default::default()
+    })
+    .with_min_update_interval(Duration::from_secs(60));
+
+    let fresh = inverter.update_state().expect("fresh reading");
+    assert!(inverter.time_until_update() > Duration::from_secs(30));
+    let early = inverter.update_state().expect("early reading");
+    assert!(early.stale);
+    assert_eq!(early.state, fresh.state);
+}
+
+#[test]
+fn details_and_commands() {
+    let config = MockConfig::default();
+    let dtu_sn = config.dtu_sn.clone();
+    let mut inverter = start_mock(config);
+
+    assert_eq!(inverter.get_config().expect("config").dtu_sn, dtu_sn);
+    let info = inverter.get_device_info().expect("device info");
+    assert_eq!(info.model(), Some("HMS-2T"));
+    assert!(inverter.get_new_alarms().expect("alarms").is_empty());
+
+    let limit = InverterCommand::SetPowerLimit(PowerLimit::Percent(50.));
+    assert_eq!(inverter.execute(&limit).expect("power limit"), limit);
+    assert_eq!(inverter.power_limit(), Some(50.));
+}
+
+#[test]
+fn power_history_of_the_day() {
+    let mut inverter = start_mock(MockConfig::default());
+    inverter.update_state().expect("reading");
+
+    let history = inverter.get_power_history().expect("power history");
+    assert_eq!(history.inverter_serial, "114400000000");
+    assert_eq!(history.daily_energy, 2_400);
+    assert!(!history.samples.is_empty());
+    // the mock DTU records a sample every five minutes since midnight UTC
+    let start_time = history.samples[0].time;
+    assert_eq!(start_time % 86_400, 0);
+    for (i, sample) in history.samples.iter().enumerate() {
+        assert_eq!(sample.time, start_time + i as i64 * 300);
+        // 96% of the DC power of two ports at 35 V and 8 A
+        assert_eq!(sample.power, 537.6);
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let last = history.samples.last().unwrap().time;
+    assert!(last <= now && now - last < 300);
+}
+
+#[test]
+fn model_includes_rated_power() {
+    let mut inverter = start_mock(MockConfig::default()).with_rated_power(Some(800.));
+
+    let info = inverter.get_device_info().expect("device info");
+    assert_eq!(info.model(), Some("HMS-800W-2T"));
+}
+
+#[test]
+fn ended_alarm_is_not_reported_again() {
+    let alarm = |number, end_time| MockAlarm {
+        code: 141,
+        number,
+        start_time: 1_700_000_000,
+        end_time,
+    };
+    let mut inverter = start_mock(MockConfig {
+        alarm_logs: vec![
+            vec![],
+            vec![alarm(1, 0)],
+            vec![alarm(1, 1_700_000_600)],
+            vec![alarm(1, 1_700_000_600), alarm(2, 0)],
+        ],
+        ..Default::default()
+    });
+
+    assert!(inverter.get_new_alarms().expect("alarms").is_empty());
+    let new = inverter.get_new_alarms().expect("alarms");
+    assert_eq!(new.len(), 1);
+    assert_eq!((new[0].code, new[0].end_time), (141, 0));
+    // the end time is set once the alarm is over, it is still the same alarm
+    assert!(inverter.get_new_alarms().expect("alarms").is_empty());
+    let new = inverter.get_new_alarms().expect("alarms");
+    assert_eq!(new.len(), 1);
+    assert_eq!(new[0].number, 2);
+}
+
+#[test]
+fn recording_replays_identically() {
+    let path = std::env::temp_dir().join(format!("hms-capture-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut inverter = start_mock(MockConfig {
+        cache_duration: 0,
+        steps: vec![reading(30.), Step::DropConnection, reading(40.)],
+        ..Default::default()
+    })
+    .with_recorder(Some(Recorder::create(&path).unwrap()));
+    let live: Vec<_> = (0..3)
+        .map(|_| inverter.update_state().ok().map(|reading| reading.state))
+        .collect();
+    let config = inverter.get_config().expect("config");
+
+    let mut replayed = Inverter::new("replay", "")
+        .with_min_update_interval(Duration::ZERO)
+        .with_replay(Some(Replay::open(&path).unwrap()));
+    let _ = std::fs::remove_file(&path);
+    for live in live {
+        let replayed = replayed.update_state();
+        match live {
+            Some(live) => assert_eq!(replayed.expect("recorded reading").state, live),
+            None => assert!(matches!(replayed, Err(InverterError::Io(_)))),
+        }
+    }
+    assert_eq!(replayed.get_config().expect("config").dtu_sn, config.dtu_sn);
+    // the capture is exhausted
+    assert!(matches!(replayed.update_state(), Err(InverterError::Io(_))));
+}
@@ -0,0 +1,81 @@
+use hms2mqtt::protocol::{Command, Frame, FrameError, ProtocolError};
+
+fn real_data_frame() -> Frame {
+    Frame {
+        command: Command::RealData.response_id(),
+        sequence: 42,
+        payload: vec![0x08, 0x01, 0x10, 0x02],
+    }
+}
+
+#[test]
+fn frame_round_trip() {
+    let frame = real_data_frame();
+    let bytes = frame.encode();
+    assert_eq!(&bytes[..4], b"HM\xa2\x03");
+    assert_eq!(bytes.len(), 14);
+
+    let decoded = Frame::read(&mut bytes.as_slice()).expect("valid frame");
+    assert_eq!(decoded, frame);
+    assert!(decoded.check_response(Command::RealData, 42).is_ok());
+}
+
+#[test]
+fn frame_split_across_reads() {
+    // a reader that hands out a single byte per call, like a slow TCP stream
+    struct Trickle<'a>(&'a [u8]);
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    let bytes = real_data_frame().encode();
+    let decoded = Frame::read(&mut Trickle(&bytes)).expect("valid frame");
+    assert_eq!(decoded, real_data_frame());
+}
+
+#[test]
+fn frame_errors_are_told_apart() {
+    let mut bytes = real_data_frame().encode();
+    bytes[13] ^= 0xff;
+    assert!(matches!(
+        Frame::read(&mut bytes.as_slice()),
+        Err(ProtocolError::Crc { .. })
+    ));
+
+    let mut bytes = real_data_frame().encode();
+    bytes[0] = b'X';
+    assert!(matches!(
+        Frame::read(&mut bytes.as_slice()),
+        Err(ProtocolError::Frame(FrameError::Magic(_)))
+    ));
+
+    let bytes = real_data_frame().encode();
+    assert!(matches!(
+        Frame::read(&mut &bytes[..12]),
+        Err(ProtocolError::Io(_))
+    ));
+
+    let frame = real_data_frame();
+    assert!(matches!(
+        frame.check_response(Command::RealData, 43),
+        Err(ProtocolError::Frame(FrameError::Sequence { .. }))
+    ));
+    assert!(matches!(
+        frame.check_response(Command::GetConfig, 42),
+        Err(ProtocolError::Frame(FrameError::Command { .. }))
+    ));
+}
+
+#[test]
+fn command_ids() {
+    assert_eq!(Command::RealData.request_id(), [0xa3, 0x03]);
+    assert_eq!(Command::from_id([0xa2, 0x11]), Some(Command::RealDataNew));
+    assert_eq!(Command::from_id([0x00, 0x03]), None);
+}
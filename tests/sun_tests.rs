@@ -0,0 +1,59 @@
+use chrono::{NaiveDate, TimeZone, Utc};
+use hms2mqtt::sun::{sun_position, sun_times, SunSchedule, SunTimes};
+use std::time::Duration;
+
+const BERLIN: (f64, f64) = (52.52, 13.405);
+
+fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+#[test]
+fn sunrise_and_sunset() {
+    let SunTimes::Regular { sunrise, sunset } = sun_times(BERLIN.0, BERLIN.1, date(2024, 6, 21))
+    else {
+        panic!("the sun rises in Berlin");
+    };
+    // published times are 04:43 and 21:33 local time (UTC+2)
+    let expected_sunrise = Utc.with_ymd_and_hms(2024, 6, 21, 2, 43, 0).unwrap();
+    let expected_sunset = Utc.with_ymd_and_hms(2024, 6, 21, 19, 33, 0).unwrap();
+    assert!((sunrise - expected_sunrise).num_minutes().abs() <= 3);
+    assert!((sunset - expected_sunset).num_minutes().abs() <= 3);
+
+    // Tromsø
+    assert_eq!(
+        sun_times(69.65, 18.96, date(2024, 6, 21)),
+        SunTimes::PolarDay
+    );
+    assert_eq!(
+        sun_times(69.65, 18.96, date(2024, 12, 21)),
+        SunTimes::PolarNight
+    );
+}
+
+#[test]
+fn time_until_daylight() {
+    let schedule = SunSchedule::new(BERLIN.0, BERLIN.1);
+
+    let noon = Utc.with_ymd_and_hms(2024, 6, 21, 11, 0, 0).unwrap();
+    assert_eq!(schedule.time_until_daylight(noon), Duration::ZERO);
+
+    // the next morning, with half an hour of twilight before sunrise at 02:43 UTC
+    let night = Utc.with_ymd_and_hms(2024, 6, 21, 23, 0, 0).unwrap();
+    let wait = schedule.time_until_daylight(night).as_secs() / 60;
+    assert!((188..=198).contains(&wait), "{wait} minutes");
+
+    // on the other side of the globe, the UTC day does not match the local day
+    let auckland = SunSchedule::new(-36.85, 174.76);
+    let local_noon = Utc.with_ymd_and_hms(2024, 12, 21, 0, 0, 0).unwrap();
+    assert_eq!(auckland.time_until_daylight(local_noon), Duration::ZERO);
+}
+
+#[test]
+fn position_at_noon() {
+    // solar noon of the summer solstice in Berlin, the sun is due south
+    let time = Utc.with_ymd_and_hms(2024, 6, 21, 11, 7, 0).unwrap();
+    let sun = sun_position(BERLIN.0, BERLIN.1, time);
+    assert!((sun.elevation - 60.9).abs() < 0.5, "{sun:?}");
+    assert!((sun.azimuth - 180.).abs() < 2., "{sun:?}");
+}